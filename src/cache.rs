@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{ self, BufWriter };
+use std::io::prelude::*;
+use std::path::{ Path, PathBuf };
+
+use parse::Command;
+
+// The name of the file in an output directory that remembers the frames saved there
+const CACHE_FILE: &str = ".frame_cache";
+
+/// The hashes of the inputs of the frames saved in a directory, so that frames whose inputs have
+/// not changed since they were saved don't need to be rendered again.
+pub struct FrameCache {
+    path: PathBuf,
+    /// The hash of each frame, by the path it was saved to
+    hashes: HashMap<String, u64>,
+}
+
+impl FrameCache {
+    /// Load the cache of the frames saved in the directory `dir`, which is empty if nothing has
+    /// been saved there yet (or the cache can't be read).
+    pub fn load(dir: &str) -> FrameCache {
+        let path = Path::new(dir).join(CACHE_FILE);
+        let mut text = String::new();
+        let _ = File::open(&path).and_then(|mut file| file.read_to_string(&mut text));
+        // Each line is a hash (in hexadecimal) and a path
+        let hashes = text.lines().filter_map(|line| {
+            let mut parts = line.splitn(2, ' ');
+            match (parts.next().map(|hash| u64::from_str_radix(hash, 16)), parts.next()) {
+                (Some(Ok(hash)), Some(filename)) => Some((filename.to_string(), hash)),
+                _ => None,
+            }
+        }).collect();
+        FrameCache { path, hashes }
+    }
+
+    /// Whether the frame saved to `filename` is still there, and was made from inputs with the
+    /// hash `hash`.
+    pub fn unchanged(&self, filename: &str, hash: u64) -> bool {
+        self.hashes.get(filename) == Some(&hash) && Path::new(filename).exists()
+    }
+
+    /// Remember that the frame saved to `filename` was made from inputs with the hash `hash`.
+    pub fn insert(&mut self, filename: String, hash: u64) {
+        self.hashes.insert(filename, hash);
+    }
+
+    /// Write the cache to its file in the output directory.
+    pub fn save(&self) -> io::Result<()> {
+        let mut filenames: Vec<&String> = self.hashes.keys().collect();
+        filenames.sort();
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        for filename in filenames {
+            writeln!(writer, "{:016x} {}", self.hashes[filename], filename)?;
+        }
+        writer.flush()
+    }
+}
+
+// The 64-bit FNV-1a hash, which (unlike the standard library's hashers) is the same in every
+// build, so the hashes saved in a cache can be compared to those of a later version
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    // Strings are followed by a byte which can't be in them, so "ab", "c" and "a", "bc" differ
+    fn write_str(&mut self, s: &str) {
+        self.write(s.as_bytes());
+        self.write(&[0xff]);
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.write(&n.to_le_bytes());
+    }
+}
+
+/// A hash of the commands of a script, including those of included files. The lighting, camera,
+/// and materials are all given by commands, so they are part of it. Comments are not, as they
+/// don't change what is drawn.
+pub fn script_hash(cmds: &[Command]) -> u64 {
+    let mut hasher = Fnv::new();
+    hash_commands(cmds, &mut hasher);
+    hasher.0
+}
+
+fn hash_commands(cmds: &[Command], hasher: &mut Fnv) {
+    for cmd in cmds {
+        match *cmd {
+            Command::Comment(_) => {},
+            // The bodies of blocks may have comments too
+            Command::Def { name, ref params, ref body } => hash_block(format!("def {} {:?}", name, params), body, hasher),
+            Command::Repeat { ref count, var, ref body } => hash_block(format!("repeat {:?} {}", count, var), body, hasher),
+            Command::Include { path, ref body } => hash_block(format!("include {}", path), body, hasher),
+            _ => hasher.write_str(&format!("{:?}", cmd)),
+        }
+    }
+}
+
+fn hash_block(header: String, body: &[Command], hasher: &mut Fnv) {
+    hasher.write_str(&header);
+    hash_commands(body, hasher);
+    // Mark the end of the block, so commands after it are told apart from those in it
+    hasher.write_str("end");
+}
+
+/// A hash of everything that decides what a frame looks like: the hash of the script's commands,
+/// the values of the knobs in each of the frame's sub-frames (just the frame itself, without
+/// motion blur), and the width and height of the image.
+pub fn frame_hash(script: u64, sub_frames: &[HashMap<&str, f64>], width: usize, height: usize) -> u64 {
+    let mut hasher = Fnv::new();
+    hasher.write_u64(script);
+    for knobs in sub_frames {
+        let mut knobs: Vec<(&str, u64)> = knobs.iter().map(|(&knob, val)| (knob, val.to_bits())).collect();
+        knobs.sort();
+        hasher.write_u64(knobs.len() as u64);
+        for (knob, val) in knobs {
+            hasher.write_str(knob);
+            hasher.write_u64(val);
+        }
+    }
+    hasher.write_u64(width as u64);
+    hasher.write_u64(height as u64);
+    hasher.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use parse;
+    use source::Sources;
+
+    fn hash_of(script: &str) -> u64 {
+        script_hash(&parse::parse(script).unwrap())
+    }
+
+    #[test]
+    fn fnv_1a() {
+        // Test vectors from the FNV reference
+        let hash = |s: &str| {
+            let mut hasher = Fnv::new();
+            hasher.write(s.as_bytes());
+            hasher.0
+        };
+        assert_eq!(hash(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash("a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash("foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn script_changes_change_the_hash() {
+        let script = "ambient 50 50 50\nlight 255 255 255 1 1 1\nconstants shiny 0.2 0.5 0.5 0.2 0.5 0.5 0.2 0.5 0.5\n\
+                      box shiny 0 0 0 10 10 10";
+        let hash = hash_of(script);
+        assert_eq!(hash_of(script), hash);
+        assert!(hash_of(&script.replace("ambient 50", "ambient 60")) != hash);
+        assert!(hash_of(&script.replace("light 255 255 255 1 1 1", "light 255 255 255 1 1 -1")) != hash);
+        assert!(hash_of(&script.replace("0.2 0.5 0.5 0.2", "0.3 0.5 0.5 0.2")) != hash);
+        assert!(hash_of(&format!("camera 0 0 100 0 0 0 0 1 0 60\n{}", script)) != hash);
+        assert!(hash_of(&format!("resolution 100 100\n{}", script)) != hash);
+        // Comments don't change what is drawn, even in blocks
+        assert_eq!(hash_of(&format!("// a box\n{}", script)), hash);
+        let looped = "repeat 2 i\nmove i 0 0\nend\nbox 0 0 0 1 1 1";
+        assert_eq!(hash_of(&looped.replace("move i 0 0", "move i 0 0 // along x")), hash_of(looped));
+        // Moving a command out of a block does
+        assert!(hash_of("repeat 2 i\nmove i 0 0\nbox 0 0 0 1 1 1\nend") != hash_of(looped));
+    }
+
+    #[test]
+    fn included_file_changes_change_the_hash() {
+        let dir = env::temp_dir().join(format!("graphics-cache-include-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let hash_with = |included: &str| {
+            fs::write(dir.join("box.mdl"), included).unwrap();
+            let sources = Sources::from_text(&dir.join("script"), String::from("include box.mdl\nmove 1 1 1")).unwrap();
+            script_hash(&parse::parse_sources(&sources).unwrap().0)
+        };
+        let hash = hash_with("box 0 0 0 10 10 10");
+        assert_eq!(hash_with("box 0 0 0 10 10 10"), hash);
+        assert!(hash_with("box 0 0 0 10 10 20") != hash);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn frame_hash_includes_knobs_and_size() {
+        let frame = |knobs: &[(&'static str, f64)]| -> HashMap<&'static str, f64> { knobs.iter().cloned().collect() };
+        let knobs = vec![frame(&[("a", 1.0), ("b", 2.0)])];
+        let hash = frame_hash(1, &knobs, 500, 500);
+        assert_eq!(frame_hash(1, &knobs, 500, 500), hash);
+        assert!(frame_hash(2, &knobs, 500, 500) != hash);
+        assert!(frame_hash(1, &knobs, 250, 500) != hash);
+        assert!(frame_hash(1, &knobs, 500, 250) != hash);
+        assert!(frame_hash(1, &[frame(&[("a", 1.0), ("b", 3.0)])], 500, 500) != hash);
+        assert!(frame_hash(1, &[frame(&[("a", 1.0)])], 500, 500) != hash);
+        // Motion blur renders several sub-frames
+        assert!(frame_hash(1, &[knobs[0].clone(), knobs[0].clone()], 500, 500) != hash);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = env::temp_dir().join(format!("graphics-cache-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let frame = dir.join("anim0.png").display().to_string();
+        let missing = dir.join("anim1.png").display().to_string();
+        fs::write(&frame, "").unwrap();
+
+        let mut cache = FrameCache::load(dir.to_str().unwrap());
+        assert!(!cache.unchanged(&frame, 7));
+        cache.insert(frame.clone(), 7);
+        cache.insert(missing.clone(), 8);
+        cache.save().unwrap();
+
+        let cache = FrameCache::load(dir.to_str().unwrap());
+        assert!(cache.unchanged(&frame, 7));
+        assert!(!cache.unchanged(&frame, 8));
+        // Frames which have been deleted are rendered again
+        assert!(!cache.unchanged(&missing, 8));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
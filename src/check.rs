@@ -0,0 +1,431 @@
+use std::collections::{ HashMap, HashSet };
+use std::fmt;
+
+use expr::Expr;
+use parse::{ self, Command, Location, Projection };
+use source::Sources;
+use consts::MAX_REPEAT;
+
+/// A problem with a script that would make rendering it fail or behave unexpectedly, found
+/// without rendering it. `file` is the path of the file containing the problem, if it was read
+/// from a file. Problems that are `error`s would certainly make rendering fail or draw nonsense;
+/// the others might not (e.g. a knob may only be used in frames where it has a value).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub file: Option<String>,
+    pub line: usize,
+    pub message: String,
+    pub error: bool,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}, ", file)?;
+        }
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Parse and check the root script of `sources`, giving a message for each syntax error or
+/// problem found.
+pub fn check_sources(sources: &Sources) -> Vec<String> {
+    match parse::parse_sources(sources) {
+        Ok((cmds, locations)) => {
+            check(&cmds, &locations, Some(sources)).iter().map(|p| p.to_string()).collect()
+        },
+        Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+    }
+}
+
+/// Find every problem in `cmds`, which were written at `locations` in `sources` (which is None
+/// for scripts parsed with `parse::parse`). The problems are in the order of their lines.
+pub fn check<'a>(cmds: &[Command<'a>], locations: &[Location], sources: Option<&Sources>) -> Vec<Problem> {
+    let mut checker = Checker {
+        macros: HashMap::new(),
+        calling: vec![],
+        recursive_calls: vec![],
+        depth: 1,
+        problems: vec![],
+    };
+    checker.check_stack(cmds, locations);
+    checker.check_animation(cmds, locations);
+
+    let mut problems = checker.problems;
+    // Commands in blocks that are called or repeated several times are checked several times
+    problems.sort();
+    problems.dedup();
+    problems.into_iter().map(|(file, line, message, error)| {
+        Problem {
+            file: sources.map(|sources| sources.path(file).display().to_string()),
+            line,
+            message,
+            error,
+        }
+    }).collect()
+}
+
+struct Checker<'a: 'b, 'b> {
+    /// Blocks defined with `def` so far, with the locations of their commands, by name
+    macros: HashMap<&'a str, (&'b [Command<'a>], &'b [Location])>,
+    /// The blocks being called, innermost last
+    calling: Vec<&'a str>,
+    /// The calls made by blocks to themselves (directly or not) which were not followed, with
+    /// the name of the block called
+    recursive_calls: Vec<(&'a str, &'b Location)>,
+    /// The number of transformations on the stack
+    depth: usize,
+    /// The file, line, message, and whether it is an error of each problem
+    problems: Vec<(usize, usize, String, bool)>,
+}
+
+impl<'a, 'b> Checker<'a, 'b> {
+    fn report(&mut self, location: &Location, message: String) {
+        self.problems.push((location.file, location.line, message, false));
+    }
+
+    fn report_error(&mut self, location: &Location, message: String) {
+        self.problems.push((location.file, location.line, message, true));
+    }
+
+    // Follow the script as it would be run, keeping track of how many transformations would be on
+    // the stack, to find `pop`s with nothing to pop
+    fn check_stack(&mut self, cmds: &'b [Command<'a>], locations: &'b [Location]) {
+        for (cmd, location) in cmds.iter().zip(locations) {
+            match *cmd {
+                Command::Push => self.depth += 1,
+                Command::Pop => {
+                    if self.depth == 1 {
+                        self.report_error(location, String::from("'pop' with only one transformation on the stack (every 'pop' needs a 'push' before it)"));
+                    } else {
+                        self.depth -= 1;
+                    }
+                },
+                Command::Def { name, ref body, .. } => {
+                    self.macros.insert(name, (body, &location.body));
+                },
+                Command::Call(name, _) => {
+                    // A block that calls itself is only followed once
+                    if let Some(&(body, body_locations)) = self.macros.get(name) {
+                        if self.calling.contains(&name) {
+                            self.recursive_calls.push((name, location));
+                        } else {
+                            let before = self.depth;
+                            self.calling.push(name);
+                            self.check_stack(body, body_locations);
+                            self.calling.pop();
+                            // Each time the block calls itself, it would pop more again
+                            let popped = self.depth < before;
+                            let (calls, rest) = self.recursive_calls.drain(..)
+                                .partition(|&(called, _)| called == name);
+                            self.recursive_calls = rest;
+                            if popped {
+                                for (_, call) in calls {
+                                    self.report(call, format!("'{}' calls itself, but pops more than it pushes, so it may 'pop' with nothing pushed", name));
+                                }
+                            }
+                        }
+                    }
+                },
+                Command::Repeat { ref count, ref body, .. } => {
+                    // Without a constant count, assume the body is run once
+                    let constant = count.constant_value();
+                    if let Some(times) = constant.filter(|&times| times > MAX_REPEAT as f64) {
+                        self.report_error(location, format!("Cannot repeat {} times (the most is {})", times, MAX_REPEAT));
+                    }
+                    let count = (constant.unwrap_or(1.0) as usize).min(MAX_REPEAT);
+                    for _ in 0..count {
+                        let before = self.depth;
+                        self.check_stack(body, &location.body);
+                        // If a run of the body doesn't pop more than it pushes, running it again
+                        // can't find any new problems
+                        if self.depth >= before {
+                            break;
+                        }
+                        if constant.is_none() {
+                            self.report(location, String::from("The body of 'repeat' pops more than it pushes, so repeating it more than once may 'pop' with nothing pushed"));
+                        }
+                    }
+                },
+                Command::Include { ref body, .. } => self.check_stack(body, &location.body),
+                _ => {},
+            }
+        }
+    }
+
+    // Check that the frames given to `vary`, `keyframes`, and `tween` are in the animation, and
+    // that every knob has a value in every frame it could be used in
+    fn check_animation(&mut self, cmds: &[Command<'a>], locations: &[Location]) {
+        let top = top_level(cmds, locations);
+        let frames = top.iter().filter_map(|&(cmd, _)| {
+            if let Command::Frames(frames) = *cmd { Some(frames) } else { None }
+        }).next_back();
+
+        // The frames (first and last) in which each animated knob is given a value
+        let mut animated: HashMap<&'a str, Vec<(usize, usize)>> = HashMap::new();
+        // Knobs given values by `set`, which have values in every frame
+        let mut set = HashSet::new();
+        for &(cmd, location) in &top {
+            let (what, fst_frame, last_frame) = match *cmd {
+                Command::Vary(ref v) => {
+                    animated.entry(v.knob).or_default().push((v.fst_frame, v.last_frame));
+                    (format!("'vary' of knob '{}'", v.knob), v.fst_frame, v.last_frame)
+                },
+                Command::Keyframes(ref k) => {
+                    let (fst_frame, last_frame) = (k.keys[0].0, k.keys[k.keys.len() - 1].0);
+                    animated.entry(k.knob).or_default().push((fst_frame, last_frame));
+                    (format!("'keyframes' of knob '{}'", k.knob), fst_frame, last_frame)
+                },
+                Command::Tween { fst_frame, last_frame, from, to } => {
+                    (format!("'tween' from '{}' to '{}'", from, to), fst_frame, last_frame)
+                },
+                Command::MotionBlur { shutter, .. } => {
+                    if !(shutter > 0.0 && shutter <= 1.0) {
+                        self.report(location, format!("The shutter of 'motionblur' must be more than 0 and at most 1, not {}", shutter));
+                    }
+                    continue;
+                },
+                _ => continue,
+            };
+            // Keyframes are in order, and one key on its own is fine
+            let keyframes = matches!(*cmd, Command::Keyframes(..));
+            if fst_frame == last_frame && !keyframes {
+                self.report_error(location, format!("{} starts and ends on frame {}", what, fst_frame));
+            } else if last_frame < fst_frame {
+                self.report_error(location, format!("{} ends on frame {}, before it starts on frame {}", what, last_frame, fst_frame));
+            }
+            match frames {
+                Some(frames) if fst_frame.max(last_frame) >= frames => {
+                    self.report(location, format!("{} goes past the last frame (there are {} frames, numbered from 0 to {})",
+                                                  what, frames, frames.saturating_sub(1)));
+                },
+                Some(_) => {},
+                None => {
+                    self.report(location, format!("{} does nothing, since the script has no 'frames' command", what));
+                },
+            }
+        }
+
+        // The first use of each knob, in the order they are used in the script
+        let mut uses = vec![];
+        knob_uses(cmds, locations, &mut vec![], &mut set, &mut uses);
+        let mut seen = HashSet::new();
+        for (knob, location) in uses {
+            if !seen.insert(knob) || set.contains(knob) {
+                continue;
+            }
+            match (animated.get(knob), frames) {
+                (Some(ranges), Some(frames)) => {
+                    let missing = frames_without_value(ranges, frames);
+                    if !missing.is_empty() {
+                        self.report(location, format!("Knob '{}' is used, but has no value in frames {}", knob, missing.join(", ")));
+                    }
+                },
+                _ => {
+                    self.report(location, format!("Knob '{}' is used, but is never given a value with 'set', 'vary', or 'keyframes'", knob));
+                },
+            }
+        }
+    }
+}
+
+// The commands in `cmds` which are not in blocks, including the commands of included files, with
+// their locations
+fn top_level<'a, 'b>(cmds: &'b [Command<'a>], locations: &'b [Location]) -> Vec<(&'b Command<'a>, &'b Location)> {
+    let mut top = vec![];
+    for (cmd, location) in cmds.iter().zip(locations) {
+        match *cmd {
+            Command::Include { ref body, .. } => top.extend(top_level(body, &location.body)),
+            _ => top.push((cmd, location)),
+        }
+    }
+    top
+}
+
+// Find the knobs used in `cmds` (with the location of each use) and the knobs given values by
+// `set`. `vars` are the variables in scope, which `move`, `rotate`, and `scale` can use in place
+// of knobs.
+fn knob_uses<'a, 'b>(cmds: &'b [Command<'a>], locations: &'b [Location], vars: &mut Vec<&'a str>,
+                     set: &mut HashSet<&'a str>, uses: &mut Vec<(&'a str, &'b Location)>) {
+    for (cmd, location) in cmds.iter().zip(locations) {
+        let mut knobs = vec![];
+        for e in exprs(cmd) {
+            e.knobs(&mut knobs);
+        }
+        match *cmd {
+            Command::Move { knob: Some(knob), .. } |
+            Command::Rotate(_, _, Some(knob)) |
+            Command::Scale { knob: Some(knob), .. } |
+            Command::Circle { knob: Some(knob), .. } |
+            Command::Bezier { knob: Some(knob), .. } |
+            Command::Hermite { knob: Some(knob), .. } if !vars.contains(&knob) => {
+                knobs.push(knob);
+            },
+            Command::Set(knob, _) => {
+                set.insert(knob);
+            },
+            _ => {},
+        }
+        uses.extend(knobs.into_iter().map(|knob| (knob, location)));
+
+        let scope = vars.len();
+        match *cmd {
+            Command::Def { ref params, ref body, .. } => {
+                vars.extend(params);
+                knob_uses(body, &location.body, vars, set, uses);
+            },
+            Command::Repeat { var, ref body, .. } => {
+                vars.push(var);
+                knob_uses(body, &location.body, vars, set, uses);
+            },
+            Command::Include { ref body, .. } => knob_uses(body, &location.body, vars, set, uses),
+            _ => {},
+        }
+        vars.truncate(scope);
+    }
+}
+
+// The numeric arguments of a command (but not those of the commands in its body)
+fn exprs<'a, 'b>(cmd: &'b Command<'a>) -> Vec<&'b Expr<'a>> {
+    match *cmd {
+        Command::Move { ref x, ref y, ref z, .. } |
+        Command::Scale { ref x, ref y, ref z, .. } => vec![x, y, z],
+        Command::Rotate(_, ref degrees, _) => vec![degrees],
+        Command::Box { ref x, ref y, ref z, ref w, ref h, ref d, .. } => vec![x, y, z, w, h, d],
+        Command::Sphere { ref x, ref y, ref z, ref r, .. } => vec![x, y, z, r],
+        Command::Torus { ref x, ref y, ref z, ref r0, ref r1, .. } => vec![x, y, z, r0, r1],
+        Command::Line { ref x0, ref y0, ref z0, ref x1, ref y1, ref z1, .. } => vec![x0, y0, z0, x1, y1, z1],
+        Command::Circle { ref x, ref y, ref z, ref r, .. } => vec![x, y, z, r],
+        Command::Bezier { ref p0, ref p1, ref p2, ref p3, .. } => {
+            p0.iter().chain(p1).chain(p2).chain(p3).collect()
+        },
+        Command::Hermite { ref p0, ref p1, ref m0, ref m1, .. } => {
+            p0.iter().chain(p1).chain(m0).chain(m1).collect()
+        },
+        Command::Set(_, ref val) | Command::SetKnobs(ref val) => vec![val],
+        Command::Ambient(ref r, ref g, ref b) => vec![r, g, b],
+        Command::Light(ref r, ref g, ref b, ref x, ref y, ref z) => vec![r, g, b, x, y, z],
+        Command::Camera { ref eye, ref aim, ref up, ref projection } => {
+            let mut exprs: Vec<_> = eye.iter().chain(aim).chain(up).collect();
+            match *projection {
+                Projection::Perspective { ref fov } => exprs.push(fov),
+                Projection::Orthographic { ref left, ref right, ref bottom, ref top, ref near, ref far } => {
+                    exprs.extend(vec![left, right, bottom, top, near, far]);
+                },
+            }
+            exprs
+        },
+        Command::Call(_, ref args) => args.iter().collect(),
+        Command::Repeat { ref count, .. } => vec![count],
+        _ => vec![],
+    }
+}
+
+// Describe the frames (from 0 to `frames` - 1) not covered by any of `ranges`, e.g. "0 to 4, 9"
+fn frames_without_value(ranges: &[(usize, usize)], frames: usize) -> Vec<String> {
+    let has_value = |frame: usize| ranges.iter().any(|&(fst, last)| fst <= frame && frame <= last);
+    let mut missing = vec![];
+    let mut frame = 0;
+    while frame < frames {
+        if has_value(frame) {
+            frame += 1;
+            continue;
+        }
+        let start = frame;
+        while frame < frames && !has_value(frame) {
+            frame += 1;
+        }
+        if frame - 1 == start {
+            missing.push(start.to_string());
+        } else {
+            missing.push(format!("{} to {}", start, frame - 1));
+        }
+    }
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    // The problems found in the script `script`
+    fn problems(script: &str) -> Vec<String> {
+        let sources = Sources::from_text(Path::new("test.mdl"), script.to_string()).unwrap();
+        check_sources(&sources)
+    }
+
+    #[test]
+    fn pops_need_pushes() {
+        assert_eq!(problems("push\npop\npop"), vec![
+            "test.mdl, line 3: 'pop' with only one transformation on the stack (every 'pop' needs a 'push' before it)",
+        ]);
+        assert!(problems("push\nrepeat 3 i\npush\npop\nend\npop").is_empty());
+        assert_eq!(problems("def p\npop\nend\npush\ncall p\ncall p").len(), 1);
+        // Repeats and calls that may be run more times than are followed
+        assert_eq!(problems("set n 3\npush\nrepeat n i\npop\nend\nbox 0 0 0 1 1 1"), vec![
+            "test.mdl, line 3: The body of 'repeat' pops more than it pushes, so repeating it more than once may 'pop' with nothing pushed",
+        ]);
+        assert_eq!(problems("def p\npop\ncall p\nend\npush\ncall p"), vec![
+            "test.mdl, line 3: 'p' calls itself, but pops more than it pushes, so it may 'pop' with nothing pushed",
+        ]);
+        assert!(problems("set n 3\nrepeat n i\npush\npop\nend").is_empty());
+    }
+
+    #[test]
+    fn repeats_have_a_limit() {
+        assert_eq!(problems("repeat (1e30) i\npush\nend"), vec![
+            "test.mdl, line 1: Cannot repeat 1000000000000000000000000000000 times (the most is 1000000)",
+        ]);
+        assert!(problems("repeat 1000000 i\nend").is_empty());
+    }
+
+    #[test]
+    fn animation_ranges() {
+        assert_eq!(problems("frames 10\nvary k 3 3 0 1\nbox 0 0 0 k 1 1"), vec![
+            "test.mdl, line 2: 'vary' of knob 'k' starts and ends on frame 3",
+            "test.mdl, line 3: Knob 'k' is used, but has no value in frames 0 to 2, 4 to 9",
+        ]);
+        assert_eq!(problems("frames 10\nvary k 0 10 0 1\nbox 0 0 0 k 1 1"), vec![
+            "test.mdl, line 2: 'vary' of knob 'k' goes past the last frame (there are 10 frames, numbered from 0 to 9)",
+        ]);
+        assert_eq!(problems("frames 10\nmotionblur 4 1.5"), vec![
+            "test.mdl, line 2: The shutter of 'motionblur' must be more than 0 and at most 1, not 1.5",
+        ]);
+        assert_eq!(problems("vary k 0 9 0 1\nset k 1"), vec![
+            "test.mdl, line 1: 'vary' of knob 'k' does nothing, since the script has no 'frames' command",
+        ]);
+        // There is no last frame to render in an animation without frames
+        assert_eq!(problems("frames 0"), vec![
+            "test.mdl, line 1, column 8: expected a positive integer, found '0'",
+        ]);
+        // One keyframe on its own is fine, but not outside the animation
+        assert_eq!(problems("frames 10\nkeyframes k 0:1\nkeyframes k 1:2 9:3\nmove 1 1 1 k"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn knobs_need_values() {
+        assert_eq!(problems("move 1 1 1 k"), vec![
+            "test.mdl, line 1: Knob 'k' is used, but is never given a value with 'set', 'vary', or 'keyframes'",
+        ]);
+        assert!(problems("set k 2\nmove 1 1 1 k").is_empty());
+        // Parameters and loop variables are not knobs
+        assert!(problems("def m x\nmove 1 1 1 x\nend\nrepeat 2 i\nscale 1 1 1 i\nend").is_empty());
+    }
+
+    #[test]
+    fn errors_and_warnings() {
+        let sources = Sources::from_text(Path::new("test.mdl"), String::from(
+            "frames 10\npop\nvary k 4 4 0 1\ntween 5 2 a b\nvary j 0 12 0 1\nmove 1 1 1 k")).unwrap();
+        let (cmds, locations) = parse::parse_sources(&sources).unwrap();
+        let errors: Vec<(usize, bool)> = check(&cmds, &locations, None).iter().map(|p| (p.line, p.error)).collect();
+        assert_eq!(errors, vec![(2, true), (3, true), (4, true), (5, false), (6, false)]);
+    }
+
+    #[test]
+    fn knobs_set_before_saving_have_values_after_tweens() {
+        let script = "frames 10\nset a 0\nset b 2\nsave_knobs k0\nset a 100\nsave_knobs k1\ntween 0 4 k0 k1\n\
+                      move 1 1 1 b\nbox 0 0 0 10 10 10";
+        assert!(problems(script).is_empty());
+    }
+}
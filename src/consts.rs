@@ -1,8 +1,28 @@
+use std::sync::atomic::{ AtomicBool, Ordering };
+
+// Size of images in pixels, unless another is given by a `resolution` command or the renderer
 pub const WIDTH: usize = 500;
 pub const HEIGHT: usize = 500;
 pub const PX_SIZE: usize = 3; // size of pixel in bytes
 
-// Number of workers to use for saving and converting frames
+// Default number of workers to use for saving and converting frames
 pub const NUM_WORKERS: usize = 8;
 
-pub const DEBUG: bool = false;
+// Default number of threads to render the frames of an animation on
+pub const NUM_RENDERERS: usize = 4;
+
+// Most times the body of a `repeat` may be run, so that a huge count is an error instead of
+// rendering (or checking) forever
+pub const MAX_REPEAT: usize = 1_000_000;
+
+// Whether to print how long each frame takes to render, save, and convert. Off unless turned on
+// with `set_debug` (e.g. by the --debug option).
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+pub fn debug() -> bool {
+    DEBUG.load(Ordering::Relaxed)
+}
+
+pub fn set_debug(debug: bool) {
+    DEBUG.store(debug, Ordering::Relaxed);
+}
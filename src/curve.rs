@@ -1,10 +1,10 @@
-#![allow(dead_code)]
-// For the moment curves are not being used
-
 use std::f64::consts::PI;
 
 use matrix::Matrix;
 
+/// Number of points used for bezier and hermite curves when no number is given.
+pub const DEFAULT_POINTS: usize = 50;
+
 /// Add a parametric curve with `points` points to `edges`.
 pub fn parametric<F, G, H>(edges: &mut Matrix, points: usize, x: F, y: G, z: H)
     where F: Fn(f64) -> f64,
@@ -23,8 +23,13 @@ pub fn parametric<F, G, H>(edges: &mut Matrix, points: usize, x: F, y: G, z: H)
     }
 }
 
-pub fn circle(edges: &mut Matrix, cx: f64, cy: f64, cz: f64, r: f64) {
-    let points = 6 * r as usize; // Almost 2 * pi * r
+/// Number of points used for a circle of radius `r` when no number is given.
+pub fn default_circle_points(r: f64) -> usize {
+    6 * r.abs() as usize // Almost 2 * pi * r
+}
+
+/// Add a circle in the XY plane to `edges`, approximated with `points` points.
+pub fn circle(edges: &mut Matrix, points: usize, cx: f64, cy: f64, cz: f64, r: f64) {
     parametric(
         edges,
         points,
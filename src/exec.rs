@@ -1,69 +1,214 @@
+use std::f64::consts::PI;
 use std::time::Instant;
-use std::collections::HashMap;
-use std::sync::mpsc::Sender;
+use std::collections::{ HashMap, HashSet };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::sync::mpsc::channel;
+use std::thread;
 
-use parse::{ self, Command, Axis };
+use parse::{ self, Command, Axis, Easing, Interpolation, Projection };
+use expr::{ Env, Expr };
 use matrix::Matrix;
 use solid;
-use render::{ self, Color, Screen, ZBuffer };
+use curve;
+use render::{ self, Camera, Material, Screen, ScreenSum, ZBuffer };
 use ppm;
+use check;
+use source::Sources;
+use cache::{ self, FrameCache };
 use consts::*;
 
-// TODO: clean up w/ regard to distinction between single-image and animation rendering
-// Ok-component of return value is None if only a static frame was generated, and Some((frames,
-// basename)) if an animation was made.
-pub fn run_script(script: &str, tx: Sender<(String, Screen)>) -> Result<Option<(usize, &str)>, String> {
-    let cmds = parse::parse(script)?;
+// How deeply `call`s may be nested before the script is assumed to recurse infinitely
+const MAX_CALL_DEPTH: usize = 64;
 
-    let mut z_buffer = ZBuffer::new();
-    let lighting_data = get_lighting_data(&cmds);
+/// A frame of an animation, as rendered by `render`.
+pub struct Frame {
+    /// The number of the frame, from 0
+    pub number: usize,
+    /// The path the frame should be saved to, e.g. `anim/spin07.png` when saving to `anim`
+    pub filename: String,
+    pub screen: Screen,
+    /// A hash of everything the frame was rendered from, to be kept in a `FrameCache`
+    pub hash: u64,
+}
 
-    match get_anim_data(&cmds) {
-        Some(anim_data) => {
-            if DEBUG {
-                println!("anim_data: {:?}", &anim_data);
-            }
+/// What `render` made from a script.
+pub enum Rendered<'a> {
+    /// A single image: the screen as it was at the end of the script
+    Still(Screen),
+    /// An animation with `frames` frames, of which those from the first to the last of
+    /// `rendered` were rendered. All of them were passed to `render`'s `on_frame` except the
+    /// `skipped` ones, which had not changed since they were saved.
+    Animation { frames: usize, basename: &'a str, rendered: (usize, usize), skipped: usize },
+}
+
+/// Parse the root script of `sources` and check it for problems, giving every error found as
+/// one message. Problems which are not errors don't stop the script from loading (see
+/// `Scene::warnings`).
+pub fn load<'a>(sources: &'a Sources) -> Result<Vec<Command<'a>>, String> {
+    let (cmds, locations) = parse::parse_sources(sources).map_err(|errors| {
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        messages.join("\n")
+    })?;
+    // Find problems that would otherwise only be found partway through rendering
+    let errors: Vec<_> = check::check(&cmds, &locations, Some(sources)).into_iter().filter(|p| p.error).collect();
+    if !errors.is_empty() {
+        let messages: Vec<String> = errors.iter().map(|p| p.to_string()).collect();
+        return Err(messages.join("\n"));
+    }
+    Ok(cmds)
+}
+
+/// Run the commands of a script. The frames of an animation are rendered on `threads` threads,
+/// and passed to `on_frame` in order, named as they would be saved in the directory `dir`. If
+/// `frames` is given, only the frames from the first to the last (inclusive) are rendered. If
+/// `size` is given, it is the width and height of the image in pixels instead of those given by
+/// the script's `resolution` command (or `WIDTH` and `HEIGHT` if it has none). If
+/// `skip_unchanged` is set, frames that are already saved in `dir` and were rendered from the same
+/// inputs (according to its `FrameCache`) are not rendered again or passed to `on_frame`.
+pub fn render<'a, F: FnMut(Frame)>(cmds: &Vec<Command<'a>>, threads: usize, frames: Option<(usize, usize)>, dir: &str,
+                                   size: Option<(usize, usize)>, skip_unchanged: bool, mut on_frame: F)
+                                   -> Result<Rendered<'a>, String> {
+    let materials = get_materials(cmds);
+    let knob_lists = get_knob_lists(cmds)?;
+    let (width, height) = size.or_else(|| get_resolution(cmds)).unwrap_or((WIDTH, HEIGHT));
 
+    match get_anim_data(cmds) {
+        Some(anim_data) => {
             let basename = anim_data.basename.unwrap_or("anim");
+            // The frames to render are from `first` up to (but not including) `end`
+            let (first, end) = match frames {
+                Some((first, last)) if first <= last && last < anim_data.frames => (first, last + 1),
+                Some((first, last)) => {
+                    return Err(format!("Cannot render frames {} to {} of an animation with {} frames (numbered from 0 to {})",
+                                       first, last, anim_data.frames, anim_data.frames.saturating_sub(1)));
+                },
+                None => (0, anim_data.frames),
+            };
+            let script_hash = cache::script_hash(cmds);
+            let cache = if skip_unchanged { Some(FrameCache::load(dir)) } else { None };
+            // The next frame to render, which each thread takes when it finishes a frame
+            let next_frame = AtomicUsize::new(first);
+            // Set when a frame fails, so that no more frames are started
+            let failed = AtomicBool::new(false);
+            let (frame_tx, frame_rx) = channel();
 
-            // Render and save each frame:
-            for i in 0..anim_data.frames {
-                if i != 0 {
-                    z_buffer.clear();
-                }
-                let mut screen = Screen::new();
-                let start = Instant::now();
-                let mut knobvals = knobs_for_frame(i, &anim_data.varies);
-                let mut transforms = vec![Matrix::identity()];
-                screen.clear_black();
-                for cmd in &cmds {
-                    run_cmd(&mut screen, &mut z_buffer, &lighting_data, &mut transforms, Some(&mut knobvals), cmd)?;
+            thread::scope(|scope| -> Result<usize, String> {
+                for _ in 0..threads.max(1) {
+                    let frame_tx = frame_tx.clone();
+                    let (next_frame, failed) = (&next_frame, &failed);
+                    let (cmds, anim_data, knob_lists, materials) = (cmds, &anim_data, &knob_lists, &materials);
+                    let cache = cache.as_ref();
+                    scope.spawn(move || {
+                        // Each thread reuses one z-buffer for all of its frames
+                        let mut z_buffer = ZBuffer::new(width, height);
+                        loop {
+                            let i = next_frame.fetch_add(1, Ordering::SeqCst);
+                            if i >= end || failed.load(Ordering::SeqCst) {
+                                break;
+                            }
+                            let times = sub_frame_times(i, anim_data);
+                            let knobvals: Vec<_> = times.iter().map(|&time| knobs_at(time, anim_data, knob_lists)).collect();
+                            let hash = cache::frame_hash(script_hash, &knobvals, width, height);
+                            let filename = anim_frame_filename(dir, anim_data.frames, basename, i);
+                            // Frames that are already saved are sent as None
+                            let screen = match cache {
+                                Some(cache) if cache.unchanged(&filename, hash) => Ok(None),
+                                _ => {
+                                    let sub_frames = times.into_iter().zip(knobvals).collect();
+                                    render_frame(cmds, i, sub_frames, !knob_lists.is_empty(), materials, &mut z_buffer).map(Some)
+                                },
+                            };
+                            if screen.is_err() {
+                                failed.store(true, Ordering::SeqCst);
+                            }
+                            // Stop if the frames are no longer wanted
+                            if frame_tx.send((i, filename, hash, screen)).is_err() {
+                                break;
+                            }
+                        }
+                    });
                 }
-                if DEBUG {
-                    let elapsed = start.elapsed();
-                    println!("Took: {}", elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000);
+                drop(frame_tx);
+
+                // Frames finish out of order, so keep each one until the frames before it are sent
+                let mut finished = HashMap::new();
+                let mut next_to_send = first;
+                let mut skipped = 0;
+                for (i, filename, hash, screen) in frame_rx {
+                    finished.insert(i, (filename, hash, screen));
+                    while let Some((filename, hash, screen)) = finished.remove(&next_to_send) {
+                        match screen? {
+                            Some(screen) => on_frame(Frame { number: next_to_send, filename, screen, hash }),
+                            None => skipped += 1,
+                        }
+                        next_to_send += 1;
+                    }
                 }
-                let filename = anim_frame_filename(anim_data.frames, basename, i);
-                tx.send((filename, screen));
-            }
-            Ok(Some((anim_data.frames, basename)))
+                Ok(skipped)
+            }).map(|skipped| Rendered::Animation {
+                frames: anim_data.frames, basename, rendered: (first, end - 1), skipped,
+            })
         },
 
         None => {
-            let mut screen = Screen::new();
-            let mut transforms = vec![Matrix::identity()];
-            for cmd in &cmds {
-                run_cmd(&mut screen, &mut z_buffer, &lighting_data, &mut transforms, None, cmd)?;
+            if let Some((first, last)) = frames {
+                return Err(format!("Cannot render frames {} to {} of a still image (the script has no 'frames' command)", first, last));
+            }
+            let mut z_buffer = ZBuffer::new(width, height);
+            let mut screen = Screen::new(width, height);
+            // Knobs in still images only have the values given to them by `set`
+            let mut knobvals = HashMap::new();
+            {
+                let mut state = State::new(&mut screen, &mut z_buffer, &materials, &mut knobvals, None);
+                for cmd in cmds {
+                    run_cmd(&mut state, cmd)?;
+                }
             }
-            Ok(None)
+            Ok(Rendered::Still(screen))
         }
     }
 }
 
-// Generate the filename used for the nth frame of an animation
-pub fn anim_frame_filename(frames: usize, basename: &str, n: usize) -> String {
+// Draw the `i`th frame of an animation by averaging `sub_frames`, each a time (in frames) and the
+// values of the knobs at that time, using `z_buffer` (which may have been used for another frame).
+// Without motion blur, the only sub-frame is the frame itself. If `sets_saved`, the knobs animated
+// in a sub-frame keep their animated values over those given by `set` (see `State::animated`).
+fn render_frame<'a>(cmds: &Vec<Command<'a>>, i: usize, sub_frames: Vec<(f64, HashMap<&'a str, f64>)>, sets_saved: bool,
+                    materials: &HashMap<&'a str, Material>, z_buffer: &mut ZBuffer) -> Result<Screen, String> {
+    let (width, height) = (z_buffer.width(), z_buffer.height());
+    let mut screen = Screen::new(width, height);
+    let start = Instant::now();
+    let mut sum = if sub_frames.len() > 1 { Some(ScreenSum::new(width, height)) } else { None };
+    for (time, mut knobvals) in sub_frames {
+        z_buffer.clear();
+        screen.clear_black();
+        {
+            let animated = if sets_saved { knobvals.keys().cloned().collect() } else { HashSet::new() };
+            let mut state = State::new(&mut screen, z_buffer, materials, &mut knobvals, Some(i));
+            state.time = time;
+            state.animated = animated;
+            for cmd in cmds {
+                run_cmd(&mut state, cmd)?;
+            }
+        }
+        if let Some(ref mut sum) = sum {
+            sum.add(&screen);
+        }
+    }
+    if let Some(sum) = sum {
+        screen = sum.average();
+    }
+    if debug() {
+        let elapsed = start.elapsed();
+        println!("Frame {} took: {}", i, elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000);
+    }
+    Ok(screen)
+}
+
+// Generate the filename used for the nth frame of an animation saved in the directory `dir`
+pub fn anim_frame_filename(dir: &str, frames: usize, basename: &str, n: usize) -> String {
     let digits_for_name = decimal_digits(frames);
-    format!("anim/{}{:0digits$}.png", basename, n, digits=digits_for_name)
+    format!("{}/{}{:0digits$}.png", dir, basename, n, digits=digits_for_name)
 }
 
 fn decimal_digits(mut n: usize) -> usize {
@@ -75,29 +220,67 @@ fn decimal_digits(mut n: usize) -> usize {
     count
 }
 
+// The commands in `cmds` which are not in blocks, including the commands of included files
+fn top_level<'a, 'b>(cmds: &'b Vec<Command<'a>>) -> Vec<&'b Command<'a>> {
+    let mut top = vec![];
+    for cmd in cmds {
+        match *cmd {
+            Command::Include { ref body, .. } => top.extend(top_level(body)),
+            _ => top.push(cmd),
+        }
+    }
+    top
+}
+
+// The width and height given by the last `resolution` command, if any
+fn get_resolution<'a>(commands: &Vec<Command<'a>>) -> Option<(usize, usize)> {
+    top_level(commands).into_iter().filter_map(|cmd| match *cmd {
+        Command::Resolution(width, height) => Some((width, height)),
+        _ => None,
+    }).next_back()
+}
+
 #[derive(Debug)]
 struct AnimData<'a> {
     frames: usize,
     basename: Option<&'a str>,
-    varies: Vec<parse::Variation<'a>>
+    varies: Vec<parse::Variation<'a>>,
+    keyframes: Vec<parse::Keyframes<'a>>,
+    // The first and last frames of each `tween`, and the names of the knob lists it blends
+    tweens: Vec<(usize, usize, &'a str, &'a str)>,
+    // The number of sub-frames averaged into each frame and the fraction of the time between
+    // frames they are spread over, if `motionblur` is used
+    motion_blur: Option<(usize, f64)>,
 }
 
 fn get_anim_data<'a>(commands: &Vec<Command<'a>>) -> Option<AnimData<'a>> {
     let mut mframes = None;
     let mut mbasename = None;
     let mut varies = vec![];
+    let mut keyframes = vec![];
+    let mut tweens = vec![];
+    let mut motion_blur = None;
 
-    for cmd in commands {
-        match cmd {
-            &Command::Frames(f) => {
+    for cmd in top_level(commands) {
+        match *cmd {
+            Command::Frames(f) => {
                 mframes = Some(f);
             },
-            &Command::Basename(s) => {
+            Command::Basename(s) => {
                 mbasename = Some(s);
             },
-            &Command::Vary(ref variation) => {
+            Command::Vary(ref variation) => {
                 varies.push(variation.clone());
             },
+            Command::Keyframes(ref track) => {
+                keyframes.push(track.clone());
+            },
+            Command::Tween { fst_frame, last_frame, from, to } => {
+                tweens.push((fst_frame, last_frame, from, to));
+            },
+            Command::MotionBlur { samples, shutter } => {
+                motion_blur = Some((samples, shutter));
+            },
             _ => {}
         }
     }
@@ -106,213 +289,867 @@ fn get_anim_data<'a>(commands: &Vec<Command<'a>>) -> Option<AnimData<'a>> {
         return Some(AnimData {
             frames: frames,
             basename: mbasename,
-            varies: varies
+            varies,
+            keyframes,
+            tweens,
+            motion_blur,
         });
     }
 
-    if varies.len() > 0 {
-        if DEBUG {
-            println!("WARNING: found 'vary' but not 'frames'");
-        }
-    }
-
     return None;
 }
 
+/// The lights that shapes are drawn with, as set so far by `ambient` and `light` commands.
+#[derive(Clone)]
 pub struct LightingData {
     pub ambient: Option<(f64, f64, f64)>, // r, g, b
     pub lights: Vec<(f64, f64, f64, f64, f64, f64)> // r, g, b, x, y, z
 }
 
-fn get_lighting_data(cmds: &Vec<Command>) -> LightingData {
-    // Count `light` commands in cmds (so we can set capacity of the Vec):
-    let mut num_lights = 0;
-    for cmd in cmds {
-        if let &Command::Light(..) = cmd {
-            num_lights += 1;
+impl LightingData {
+    fn new() -> LightingData {
+        LightingData {
+            ambient: None,
+            lights: vec![],
         }
     }
+}
 
-    // Determine values of `ambient` and `lights` fields
-    let mut lights = Vec::with_capacity(num_lights);
-    let mut ambient = None;
-    for cmd in cmds {
-        match cmd {
-            &Command::Ambient(r, g, b) => {
-                ambient = Some((r, g, b));
-            },
-            &Command::Light(r, g, b, x, y, z) => {
-                lights.push((r, g, b, x, y, z));
-            },
-            _ => {},
+// Collect the materials defined by `constants` commands, by name
+fn get_materials<'a>(cmds: &Vec<Command<'a>>) -> HashMap<&'a str, Material> {
+    let mut materials = HashMap::new();
+    for cmd in top_level(cmds) {
+        if let Command::Constants(name, material) = *cmd {
+            materials.insert(name, material);
         }
     }
+    materials
+}
 
-    LightingData {
-        ambient: ambient,
-        lights: lights
+// Look up the material a shape should be drawn with. Shapes without constants use the default.
+fn material_for<'a>(materials: &HashMap<&'a str, Material>, constants: Option<&'a str>) -> Result<Material, String> {
+    match constants {
+        Some(name) => match materials.get(name) {
+            Some(material) => Ok(*material),
+            None => Err(format!("Constants '{}' are not defined", name)),
+        },
+        None => Ok(Material::default()),
     }
 }
 
-fn knob_val<'a>(knobs: &HashMap<&'a str, f64>, knob: &'a str) -> f64 {
-    match knobs.get(knob) {
-        Some(v) => *v,
-        None => {
-            panic!("Knob '{}' not defined for every frame", knob);
+/// Knob values saved with `save_knobs`, by the name of the knob list.
+type KnobLists<'a> = HashMap<&'a str, HashMap<&'a str, f64>>;
+
+// Run the `set`, `setknobs`, and `save_knobs` commands in order to find the knob lists, and check
+// that every `tween` blends between two saved lists with the same knobs
+fn get_knob_lists<'a>(cmds: &Vec<Command<'a>>) -> Result<KnobLists<'a>, String> {
+    let cmds = top_level(cmds);
+    let mut knob_lists = HashMap::new();
+    // Only evaluate `set`s if the knobs are saved, so scripts without knob lists can use `set`
+    // with values that are only known while rendering
+    if cmds.iter().any(|cmd| matches!(**cmd, Command::SaveKnobs(..))) {
+        let mut knobs = HashMap::new();
+        for cmd in &cmds {
+            match **cmd {
+                Command::Set(knob, ref val) => {
+                    let val = val.eval(&SavedKnobs(&knobs))?;
+                    knobs.insert(knob, val);
+                },
+                Command::SetKnobs(ref val) => {
+                    let val = val.eval(&SavedKnobs(&knobs))?;
+                    for v in knobs.values_mut() {
+                        *v = val;
+                    }
+                },
+                Command::SaveKnobs(name) => {
+                    knob_lists.insert(name, knobs.clone());
+                },
+                _ => {},
+            }
+        }
+    }
+
+    for cmd in &cmds {
+        if let Command::Tween { from, to, .. } = **cmd {
+            let lookup = |name| knob_lists.get(name).ok_or_else(|| {
+                format!("Knob list '{}' was not saved with 'save_knobs'", name)
+            });
+            let (from_knobs, to_knobs) = (lookup(from)?, lookup(to)?);
+            let mut missing = from_knobs.keys().filter(|knob| !to_knobs.contains_key(*knob)).map(|knob| (knob, to))
+                .chain(to_knobs.keys().filter(|knob| !from_knobs.contains_key(*knob)).map(|knob| (knob, from)));
+            if let Some((knob, list)) = missing.next() {
+                return Err(format!("Knob '{}' is not in knob list '{}', so it cannot be tweened between '{}' and '{}'",
+                                   knob, list, from, to));
+            }
+        }
+    }
+    Ok(knob_lists)
+}
+
+// The knob values set so far, for evaluating `set` commands before knobs are saved
+struct SavedKnobs<'t, 'a: 't>(&'t HashMap<&'a str, f64>);
+
+impl<'t, 'a> Env<'a> for SavedKnobs<'t, 'a> {
+    fn var(&self, name: &'a str) -> Result<f64, String> {
+        Err(format!("Variable '{}' has no value", name))
+    }
+
+    fn knob(&self, name: &'a str) -> Result<f64, String> {
+        match self.0.get(name) {
+            Some(x) => Ok(*x),
+            None => Err(format!("Knob '{}' has no value before it is set", name)),
+        }
+    }
+
+    fn frame(&self) -> Result<f64, String> {
+        Err(String::from("'frame' has no value when knobs are saved with 'save_knobs'"))
+    }
+}
+
+
+// The times (in frames) of the sub-frames averaged into frame `frame`, which are spread evenly over
+// the time the shutter is open, centered on the frame. Times before the first frame or after the
+// last are moved to it.
+fn sub_frame_times(frame: usize, anim_data: &AnimData) -> Vec<f64> {
+    match anim_data.motion_blur {
+        Some((samples, shutter)) => {
+            let last = anim_data.frames.saturating_sub(1) as f64;
+            (0..samples).map(|k| {
+                let offset = shutter * ((k as f64 + 0.5) / samples as f64 - 0.5);
+                (frame as f64 + offset).max(0.0).min(last)
+            }).collect()
         },
+        None => vec![frame as f64],
     }
 }
 
-fn optknob_val<'a>(optknobs: Option<&HashMap<&'a str, f64>>, optknob: Option<&'a str>) -> f64 {
-    if let (Some(knobs), Some(knob)) = (optknobs, optknob) {
-        knob_val(knobs, knob)
-    } else {
-        1.0 // default to 1.0 to not scale values at all
+// The values of the knobs at `time` (in frames), which may be between frames. Knobs which are
+// animated on both sides of `time` but not in between keep their values from the nearest frame.
+fn knobs_at<'a>(time: f64, anim_data: &AnimData<'a>, knob_lists: &KnobLists<'a>) -> HashMap<&'a str, f64> {
+    let mut knob_vals = knobs_for_frame(time.round(), anim_data, knob_lists);
+    if time != time.round() {
+        knob_vals.extend(knobs_for_frame(time, anim_data, knob_lists));
     }
+    knob_vals
 }
 
-fn knobs_for_frame<'a>(frame: usize, varies: &Vec<parse::Variation<'a>>) -> HashMap<&'a str, f64> {
+// Keyframes take precedence over `vary`s of the same knob, which take precedence over `tween`s.
+// `frame` may be between frames, for motion blur.
+fn knobs_for_frame<'a>(frame: f64, anim_data: &AnimData<'a>, knob_lists: &KnobLists<'a>) -> HashMap<&'a str, f64> {
     let mut knob_vals = vec![];
-    for vary in varies {
-        if vary.fst_frame <= frame && frame <= vary.last_frame {
-            let progress = (frame - vary.fst_frame) as f64 / (vary.last_frame - vary.fst_frame) as f64;
-            let val = vary.min_val + (vary.max_val - vary.min_val) * progress;
+    for &(fst_frame, last_frame, from, to) in &anim_data.tweens {
+        if fst_frame as f64 <= frame && frame <= last_frame as f64 {
+            let progress = (frame - fst_frame as f64) / (last_frame - fst_frame) as f64;
+            // get_knob_lists checked that both lists exist and have the same knobs
+            let to = &knob_lists[to];
+            for (&knob, &from_val) in &knob_lists[from] {
+                knob_vals.push((knob, from_val + (to[knob] - from_val) * progress));
+            }
+        }
+    }
+    for vary in &anim_data.varies {
+        if vary.fst_frame as f64 <= frame && frame <= vary.last_frame as f64 {
+            let progress = (frame - vary.fst_frame as f64) / (vary.last_frame - vary.fst_frame) as f64;
+            let val = vary.min_val + (vary.max_val - vary.min_val) * ease(vary.easing, progress);
             knob_vals.push((vary.knob, val))
         }
         // Otherwise, this 'vary' doesn't apply to the current frame.
     }
+    for track in &anim_data.keyframes {
+        if let Some(val) = keyframe_val(track, frame) {
+            knob_vals.push((track.knob, val));
+        }
+    }
     return knob_vals.into_iter().collect();
 }
 
+/// Get the value of a knob with keyframes in `frame` (which may be between frames), if the frame is
+/// between its first and last keys.
+fn keyframe_val(track: &parse::Keyframes, frame: f64) -> Option<f64> {
+    let keys = &track.keys;
+    // The index of the key that starts the segment containing `frame`
+    let i = match keys.iter().rposition(|&(f, _)| f as f64 <= frame) {
+        Some(i) if i == keys.len() - 1 => {
+            return if keys[i].0 as f64 == frame { Some(keys[i].1) } else { None };
+        },
+        Some(i) => i,
+        None => return None,
+    };
+    let ((f0, v0), (f1, v1)) = (keys[i], keys[i + 1]);
+    let t = (frame - f0 as f64) / (f1 - f0) as f64;
+    Some(match track.interpolation {
+        Interpolation::Linear => v0 + (v1 - v0) * t,
+        Interpolation::CatmullRom => {
+            // The slope (per frame) at each key, from its neighbors, or from the key itself at
+            // the ends of the track
+            let slope = |j: usize| {
+                let (a, b) = (keys[j.saturating_sub(1)], keys[(j + 1).min(keys.len() - 1)]);
+                (b.1 - a.1) / (b.0 - a.0) as f64
+            };
+            let h = (f1 - f0) as f64;
+            let (m0, m1) = (slope(i) * h, slope(i + 1) * h);
+            let (t2, t3) = (t * t, t * t * t);
+            (2.0 * t3 - 3.0 * t2 + 1.0) * v0 + (t3 - 2.0 * t2 + t) * m0 +
+                (-2.0 * t3 + 3.0 * t2) * v1 + (t3 - t2) * m1
+        },
+        Interpolation::Bezier => {
+            // The control points are v0, v0, v1, v1
+            let s = 1.0 - t;
+            (s * s * s + 3.0 * s * s * t) * v0 + (3.0 * s * t * t + t * t * t) * v1
+        },
+    })
+}
+
+/// Map `t` (the progress through a `vary`, from 0 to 1) to the fraction of the way from
+/// `min_val` to `max_val` the knob should be. Every easing starts at 0 and ends at 1.
+fn ease(easing: Easing, t: f64) -> f64 {
+    match easing {
+        Easing::Linear => t,
+        Easing::EaseIn => t * t,
+        Easing::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+        Easing::EaseInOut => {
+            if t < 0.5 {
+                2.0 * t * t
+            } else {
+                1.0 - 2.0 * (1.0 - t) * (1.0 - t)
+            }
+        },
+        Easing::Cubic => {
+            if t < 0.5 {
+                4.0 * t * t * t
+            } else {
+                1.0 - 4.0 * (1.0 - t) * (1.0 - t) * (1.0 - t)
+            }
+        },
+        Easing::Sine => (1.0 - (PI * t).cos()) / 2.0,
+        Easing::Step => if t < 1.0 { 0.0 } else { 1.0 },
+        Easing::Bounce => {
+            // Four parabolic arcs, each a fraction of the height of the last
+            let (n, d) = (7.5625, 2.75);
+            if t < 1.0 / d {
+                n * t * t
+            } else if t < 2.0 / d {
+                let t = t - 1.5 / d;
+                n * t * t + 0.75
+            } else if t < 2.5 / d {
+                let t = t - 2.25 / d;
+                n * t * t + 0.9375
+            } else {
+                let t = t - 2.625 / d;
+                n * t * t + 0.984375
+            }
+        },
+        Easing::Elastic => {
+            if t <= 0.0 || t >= 1.0 {
+                t
+            } else {
+                // A decaying sine wave around 1
+                2f64.powf(-10.0 * t) * ((10.0 * t - 0.75) * 2.0 * PI / 3.0).sin() + 1.0
+            }
+        },
+    }
+}
+
+// Scale the point `p` by `t`, keeping it homogeneous
+fn scaled_point(t: f64, p: [f64; 4]) -> [f64; 4] {
+    [t * p[0], t * p[1], t * p[2], 1.0]
+}
+
 fn last<T>(v: &Vec<T>) -> &T {
     &v[v.len() - 1]
 }
 
+fn last_mut<T>(v: &mut [T]) -> &mut T {
+    let len = v.len();
+    &mut v[len - 1]
+}
+
 fn transform_last(mat: &Matrix, transforms: &mut Vec<Matrix>) {
     let len = transforms.len();
     transforms[len - 1] = &transforms[len - 1] * mat;
 }
 
-// TODO: make an Arguments struct to shrink this massive argumets list
-fn run_cmd<'a>(screen: &mut Screen, z_buffer: &mut ZBuffer, lighting: &LightingData, transforms: &mut Vec<Matrix>, knobs: Option<&mut HashMap<&'a str, f64>>, cmd: &Command<'a>) -> Result<(), String> {
-    match cmd {
-        &Command::Line { x0, y0, z0, x1, y1, z1 } => {
+/// Everything `run_cmd` reads or updates while drawing a single image.
+struct State<'a, 'b> {
+    screen: &'b mut Screen,
+    z_buffer: &'b mut ZBuffer,
+    materials: &'b HashMap<&'a str, Material>,
+    /// The value of each knob, from `set` and (in animations) from `vary`, `keyframes`, and
+    /// `tween`
+    knobs: &'b mut HashMap<&'a str, f64>,
+    transforms: Vec<Matrix>,
+    /// The lighting for each level of the stack, which `push` and `pop` save and restore with
+    /// the transformations, so that lights added after a `push` only light shapes until the `pop`
+    lighting: Vec<LightingData>,
+    /// The camera set by the last `camera` command, if any
+    camera: Option<Camera>,
+    /// Transformations saved by `save_coord_system`, by name
+    coord_systems: HashMap<&'a str, Matrix>,
+    /// Blocks defined with `def` (their parameters and commands), by name
+    macros: HashMap<&'a str, (&'b Vec<&'a str>, &'b Vec<Command<'a>>)>,
+    /// Values of variables, with one scope for the top level and for each active `call`. Only
+    /// the innermost scope is visible.
+    scopes: Vec<HashMap<&'a str, f64>>,
+    /// The number of the frame being drawn, or None for still images
+    frame: Option<usize>,
+    /// The time being drawn, in frames, which is between frames for the sub-frames of motion blur
+    time: f64,
+    /// The knobs given values by the animation in this frame, which `set` and `setknobs` outside of
+    /// called blocks don't change. This is only used when knobs are saved with `save_knobs`, as
+    /// those `set`s then define the knob lists for tweens, and otherwise just give each knob a
+    /// value for the frames where it isn't animated.
+    animated: HashSet<&'a str>,
+}
+
+impl<'a, 'b> State<'a, 'b> {
+    fn new(screen: &'b mut Screen, z_buffer: &'b mut ZBuffer, materials: &'b HashMap<&'a str, Material>, knobs: &'b mut HashMap<&'a str, f64>, frame: Option<usize>) -> State<'a, 'b> {
+        State {
+            screen,
+            z_buffer,
+            materials,
+            knobs,
+            transforms: vec![Matrix::identity()],
+            lighting: vec![LightingData::new()],
+            camera: None,
+            coord_systems: HashMap::new(),
+            macros: HashMap::new(),
+            scopes: vec![HashMap::new()],
+            frame,
+            time: frame.unwrap_or(0) as f64,
+            animated: HashSet::new(),
+        }
+    }
+
+    fn value(&self, expr: &Expr<'a>) -> Result<f64, String> {
+        expr.eval(self)
+    }
+
+    fn point(&self, p: &[Expr<'a>; 3]) -> Result<[f64; 4], String> {
+        Ok([self.value(&p[0])?, self.value(&p[1])?, self.value(&p[2])?, 1.0])
+    }
+
+    // The factor a transformation is scaled by: the value of `knob`, or 1 if there is no knob
+    fn knob_scale(&self, knob: Option<&'a str>) -> Result<f64, String> {
+        match knob {
+            // Variables (e.g. loop indices) can be used in place of knobs
+            Some(name) => match last(&self.scopes).get(name) {
+                Some(x) => Ok(*x),
+                None => self.knob(name),
+            },
+            None => Ok(1.0),
+        }
+    }
+
+    // The transformation to draw with: the coordinate system named `cs` if there is one, and
+    // otherwise the top of the stack
+    fn transform_for(&self, cs: Option<&'a str>) -> Result<&Matrix, String> {
+        match cs {
+            Some(name) => match self.coord_systems.get(name) {
+                Some(transform) => Ok(transform),
+                None => Err(format!("Coordinate system '{}' was not saved before being used", name)),
+            },
+            None => Ok(last(&self.transforms)),
+        }
+    }
+
+    fn draw_edges(&mut self, edges: Matrix, cs: Option<&'a str>) -> Result<(), String> {
+        let edges = self.transform_for(cs)? * &edges;
+        render::edge_list(self.screen, &edges, self.camera.as_ref());
+        Ok(())
+    }
+
+    fn draw_triangles(&mut self, triangles: Matrix, constants: Option<&'a str>, cs: Option<&'a str>) -> Result<(), String> {
+        let material = material_for(self.materials, constants)?;
+        let triangles = self.transform_for(cs)? * &triangles;
+        render::triangle_list(self.screen, self.z_buffer, &triangles, last(&self.lighting), &material, self.camera.as_ref());
+        Ok(())
+    }
+}
+
+impl<'a, 'b> Env<'a> for State<'a, 'b> {
+    fn var(&self, name: &'a str) -> Result<f64, String> {
+        match last(&self.scopes).get(name) {
+            Some(x) => Ok(*x),
+            None => Err(format!("Variable '{}' has no value", name)),
+        }
+    }
+
+    fn knob(&self, name: &'a str) -> Result<f64, String> {
+        match (self.knobs.get(name), self.frame) {
+            (Some(x), _) => Ok(*x),
+            (None, Some(frame)) => Err(format!("Knob '{}' has no value in frame {}", name, frame)),
+            (None, None) => Err(format!("Knob '{}' has no value (give it one with 'set')", name)),
+        }
+    }
+
+    fn frame(&self) -> Result<f64, String> {
+        Ok(self.time)
+    }
+}
+
+fn run_cmd<'a, 'b>(state: &mut State<'a, 'b>, cmd: &'b Command<'a>) -> Result<(), String> {
+    match *cmd {
+        Command::Line { ref x0, ref y0, ref z0, ref x1, ref y1, ref z1, cs } => {
             let mut edges = Matrix::empty();
             edges.push_edge(
-                [x0, y0, z0, 1.0],
-                [x1, y1, z1, 1.0]);
-            edges = last(&transforms) * &edges;
-            render::edge_list(screen, &edges);
-            Ok(())
+                [state.value(x0)?, state.value(y0)?, state.value(z0)?, 1.0],
+                [state.value(x1)?, state.value(y1)?, state.value(z1)?, 1.0]);
+            state.draw_edges(edges, cs)
+        },
+
+        Command::Circle { ref x, ref y, ref z, ref r, segments, knob, cs } => {
+            let t = state.knob_scale(knob)?;
+            let r = t * state.value(r)?;
+            let segments = segments.unwrap_or_else(|| curve::default_circle_points(r));
+            let mut edges = Matrix::empty();
+            curve::circle(&mut edges, segments, t * state.value(x)?, t * state.value(y)?, t * state.value(z)?, r);
+            state.draw_edges(edges, cs)
+        },
+
+        Command::Bezier { ref p0, ref p1, ref p2, ref p3, segments, knob, cs } => {
+            let t = state.knob_scale(knob)?;
+            let mut edges = Matrix::empty();
+            curve::bezier(
+                &mut edges,
+                segments.unwrap_or(curve::DEFAULT_POINTS),
+                scaled_point(t, state.point(p0)?),
+                scaled_point(t, state.point(p1)?),
+                scaled_point(t, state.point(p2)?),
+                scaled_point(t, state.point(p3)?));
+            state.draw_edges(edges, cs)
         },
 
-        // TODO: (Parse and) draw curves as well. It was not required, but is nice to have.
+        Command::Hermite { ref p0, ref p1, ref m0, ref m1, segments, knob, cs } => {
+            let t = state.knob_scale(knob)?;
+            let mut edges = Matrix::empty();
+            curve::hermite(
+                &mut edges,
+                segments.unwrap_or(curve::DEFAULT_POINTS),
+                scaled_point(t, state.point(p0)?),
+                scaled_point(t, state.point(p1)?),
+                scaled_point(t, state.point(m0)?),
+                scaled_point(t, state.point(m1)?));
+            state.draw_edges(edges, cs)
+        },
 
-        &Command::Box { x, y, z, w, h, d } => {
+        Command::Box { ref x, ref y, ref z, ref w, ref h, ref d, constants, cs } => {
             let mut triangles = Matrix::empty();
-            solid::rect_prism(&mut triangles, x, y, z, w, h, d);
-            triangles = last(&transforms) * &triangles;
-            render::triangle_list(screen, z_buffer, &triangles, &lighting);
-            Ok(())
+            solid::rect_prism(
+                &mut triangles,
+                state.value(x)?, state.value(y)?, state.value(z)?,
+                state.value(w)?, state.value(h)?, state.value(d)?);
+            state.draw_triangles(triangles, constants, cs)
         },
 
-        &Command::Sphere { x, y, z, r } => {
+        Command::Sphere { ref x, ref y, ref z, ref r, constants, cs } => {
             let mut triangles = Matrix::empty();
-            solid::sphere(&mut triangles, x, y, z, r);
-            triangles = last(&transforms) * &triangles;
-            render::triangle_list(screen, z_buffer, &triangles, &lighting);
-            Ok(())
+            solid::sphere(&mut triangles, state.value(x)?, state.value(y)?, state.value(z)?, state.value(r)?);
+            state.draw_triangles(triangles, constants, cs)
         },
 
-        &Command::Torus { x, y, z, r0, r1 } => {
+        Command::Torus { ref x, ref y, ref z, ref r0, ref r1, constants, cs } => {
             let mut triangles = Matrix::empty();
-            solid::torus(&mut triangles, x, y, z, r0, r1);
-            triangles = last(&transforms) * &triangles;
-            render::triangle_list(screen, z_buffer, &triangles, &lighting);
+            solid::torus(
+                &mut triangles,
+                state.value(x)?, state.value(y)?, state.value(z)?,
+                state.value(r0)?, state.value(r1)?);
+            state.draw_triangles(triangles, constants, cs)
+        },
+
+        Command::Comment(..) => {
             Ok(())
         },
 
-        &Command::Push => {
-            let top = last(&transforms).clone();
-            transforms.push(top);
+        Command::Push => {
+            let top = last(&state.transforms).clone();
+            state.transforms.push(top);
+            let lighting = last(&state.lighting).clone();
+            state.lighting.push(lighting);
             Ok(())
         },
 
-        &Command::Pop => {
-            transforms.pop();
+        Command::Pop => {
+            // The transformation and lighting that the script starts with are always kept
+            if state.transforms.len() == 1 {
+                return Err(String::from("'pop' with nothing pushed (every 'pop' needs a 'push' before it)"));
+            }
+            state.transforms.pop();
+            state.lighting.pop();
+            Ok(())
+        },
+
+        Command::SaveCoordSystem(name) => {
+            let top = last(&state.transforms).clone();
+            state.coord_systems.insert(name, top);
             Ok(())
         },
 
-        &Command::Scale { x, y, z, knob } => {
-            let t = optknob_val(knobs.map(|x| &*x), knob);
-            transform_last(&Matrix::dilation_xyz(t * x, t * y, t * z), transforms);
+        Command::Scale { ref x, ref y, ref z, knob } => {
+            let t = state.knob_scale(knob)?;
+            let (x, y, z) = (state.value(x)?, state.value(y)?, state.value(z)?);
+            transform_last(&Matrix::dilation_xyz(t * x, t * y, t * z), &mut state.transforms);
             Ok(())
         },
 
-        &Command::Move { x, y, z, knob } => {
-            let t = optknob_val(knobs.map(|x| &*x), knob);
-            transform_last(&Matrix::translation_xyz(t * x, t * y, t * z), transforms);
+        Command::Move { ref x, ref y, ref z, knob } => {
+            let t = state.knob_scale(knob)?;
+            let (x, y, z) = (state.value(x)?, state.value(y)?, state.value(z)?);
+            transform_last(&Matrix::translation_xyz(t * x, t * y, t * z), &mut state.transforms);
             Ok(())
         },
 
-        &Command::Rotate(axis, degrees, knob) => {
-            let t = optknob_val(knobs.map(|x| &*x), knob);
-            let radians = degrees.to_radians();
+        Command::Rotate(axis, ref degrees, knob) => {
+            let t = state.knob_scale(knob)?;
+            let radians = state.value(degrees)?.to_radians();
             let rotation = match axis {
                 Axis::X => Matrix::rotation_about_x(t * radians),
                 Axis::Y => Matrix::rotation_about_y(t * radians),
                 Axis::Z => Matrix::rotation_about_z(t * radians)
             };
-            transform_last(&rotation, transforms);
+            transform_last(&rotation, &mut state.transforms);
             Ok(())
         },
 
-        &Command::Display => {
-            ppm::display_image(&screen);
+        Command::Def { name, ref params, ref body } => {
+            state.macros.insert(name, (params, body));
             Ok(())
         },
 
-        &Command::Save(name) => {
-            ppm::save_png(&screen, name);
+        Command::Call(name, ref args) => {
+            let (params, body) = match state.macros.get(name) {
+                Some(&block) => block,
+                None => return Err(format!("Block '{}' was called before it was defined", name)),
+            };
+            if state.scopes.len() > MAX_CALL_DEPTH {
+                return Err(format!("Calls nested more than {} deep while calling '{}' (is it infinitely recursive?)", MAX_CALL_DEPTH, name));
+            }
+            // Evaluate the arguments in the caller's scope
+            let mut scope = HashMap::new();
+            for (param, arg) in params.iter().zip(args) {
+                scope.insert(*param, state.value(arg)?);
+            }
+            state.scopes.push(scope);
+            for cmd in body {
+                run_cmd(state, cmd)?;
+            }
+            state.scopes.pop();
             Ok(())
         },
 
-        &Command::Set(knob, val) => {
-            match knobs {
-                Some(knobs) => {
-                    let r = knobs.entry(knob).or_insert(0.0);
-                    *r = val;
-                },
-                None => {
-                    // TODO: instead of None, just have an empty HashMap so set can be used
-                },
+        Command::Repeat { ref count, var, ref body } => {
+            let count = state.value(count)?;
+            if count < 0.0 || count.fract() != 0.0 {
+                return Err(format!("Cannot repeat {} times", count));
+            }
+            if count > MAX_REPEAT as f64 {
+                return Err(format!("Cannot repeat {} times (the most is {})", count, MAX_REPEAT));
             }
+            // Restore any variable the loop variable shadows once the loop is done
+            let shadowed = last(&state.scopes).get(var).cloned();
+            for i in 0..count as usize {
+                last_mut(&mut state.scopes).insert(var, i as f64);
+                for cmd in body {
+                    run_cmd(state, cmd)?;
+                }
+            }
+            match shadowed {
+                Some(x) => last_mut(&mut state.scopes).insert(var, x),
+                None => last_mut(&mut state.scopes).remove(var),
+            };
             Ok(())
         },
 
-        &Command::SetKnobs(v) => {
-            match knobs {
-                Some(knobs) => {
-                    for val in knobs.values_mut() {
-                        *val = v;
-                    }
-                },
-                None => {
-                    // TODO: instead of None, just have an empty HashMap so set can be used
-                },
+        Command::Include { path, ref body } => {
+            for cmd in body {
+                run_cmd(state, cmd).map_err(|e| format!("{} (in included file '{}')", e, path))?;
             }
             Ok(())
         },
 
-        // Lighting commands already processed by get_lighting_data
-        &Command::Ambient(..) | &Command::Light(..) => {
+        Command::Display => {
+            ppm::display_image(state.screen);
             Ok(())
         },
 
-        // Knob-related commands already processed by get_anim_data
-        &Command::Frames(..) | &Command::Basename(..) | &Command::Vary { .. } => {
+        Command::Save(name) => {
+            ppm::save_png(state.screen, name);
+            Ok(())
+        },
+
+        Command::Set(knob, ref val) => {
+            let val = state.value(val)?;
+            if state.scopes.len() > 1 || !state.animated.contains(knob) {
+                state.knobs.insert(knob, val);
+            }
+            Ok(())
+        },
+
+        Command::SetKnobs(ref v) => {
+            let v = state.value(v)?;
+            let top_level = state.scopes.len() == 1;
+            for (knob, val) in state.knobs.iter_mut() {
+                if !top_level || !state.animated.contains(knob) {
+                    *val = v;
+                }
+            }
+            Ok(())
+        },
+
+        // Lights only affect the shapes drawn after them
+        Command::Ambient(ref r, ref g, ref b) => {
+            let ambient = (state.value(r)?, state.value(g)?, state.value(b)?);
+            last_mut(&mut state.lighting).ambient = Some(ambient);
+            Ok(())
+        },
+
+        Command::Light(ref r, ref g, ref b, ref x, ref y, ref z) => {
+            let light = (state.value(r)?, state.value(g)?, state.value(b)?,
+                         state.value(x)?, state.value(y)?, state.value(z)?);
+            last_mut(&mut state.lighting).lights.push(light);
+            Ok(())
+        },
+
+        // Like lights, the camera only affects the shapes drawn after it
+        Command::Camera { ref eye, ref aim, ref up, ref projection } => {
+            let (eye, aim, up) = (state.point(eye)?, state.point(aim)?, state.point(up)?);
+            let (eye, aim, up) = ([eye[0], eye[1], eye[2]], [aim[0], aim[1], aim[2]], [up[0], up[1], up[2]]);
+            let (width, height) = (state.screen.width(), state.screen.height());
+            let camera = match *projection {
+                Projection::Perspective { ref fov } => Camera::perspective(eye, aim, up, state.value(fov)?, width, height)?,
+                Projection::Orthographic { ref left, ref right, ref bottom, ref top, ref near, ref far } => {
+                    let volume = [
+                        state.value(left)?, state.value(right)?,
+                        state.value(bottom)?, state.value(top)?,
+                        state.value(near)?, state.value(far)?];
+                    Camera::orthographic(eye, aim, up, volume, width, height)?
+                },
+            };
+            state.camera = Some(camera);
+            Ok(())
+        },
+
+        // Already processed by get_resolution
+        Command::Resolution(..) => {
+            Ok(())
+        },
+
+        // Materials already processed by get_materials
+        Command::Constants(..) => {
+            Ok(())
+        },
+
+        // Animation commands already processed by get_anim_data and get_knob_lists
+        Command::Frames(..) | Command::Basename(..) | Command::Vary { .. } | Command::Keyframes(..) |
+        Command::SaveKnobs(..) | Command::Tween { .. } | Command::MotionBlur { .. } => {
             Ok(())
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EASINGS: [Easing; 9] = [
+        Easing::Linear, Easing::EaseIn, Easing::EaseOut, Easing::EaseInOut, Easing::Cubic, Easing::Sine,
+        Easing::Step, Easing::Bounce, Easing::Elastic,
+    ];
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} is not {}", actual, expected);
+    }
+
+    // The values of the knobs of the animation `script` at `time`
+    fn knobs_in(script: &'static str, time: f64) -> HashMap<&'static str, f64> {
+        let cmds = parse::parse(script).unwrap();
+        let anim_data = get_anim_data(&cmds).unwrap();
+        let knob_lists = get_knob_lists(&cmds).unwrap();
+        knobs_at(time, &anim_data, &knob_lists)
+    }
+
+    // The values of the knobs after running the commands of the animation `script` for `frame`
+    fn knobs_after(script: &'static str, frame: usize) -> HashMap<&'static str, f64> {
+        let cmds = parse::parse(script).unwrap();
+        let mut knobvals = knobs_in(script, frame as f64);
+        let animated = knobvals.keys().cloned().collect();
+        let materials = get_materials(&cmds);
+        let mut screen = Screen::new(10, 10);
+        let mut z_buffer = ZBuffer::new(10, 10);
+        {
+            let mut state = State::new(&mut screen, &mut z_buffer, &materials, &mut knobvals, Some(frame));
+            state.animated = animated;
+            for cmd in &cmds {
+                run_cmd(&mut state, cmd).unwrap();
+            }
+        }
+        knobvals
+    }
+
+    fn keyframes(keys: &[(usize, f64)], interpolation: Interpolation) -> parse::Keyframes<'static> {
+        parse::Keyframes { knob: "k", keys: keys.to_vec(), interpolation }
+    }
+
+    #[test]
+    fn easings_start_at_0_and_end_at_1() {
+        for &easing in &EASINGS {
+            assert_close(ease(easing, 0.0), 0.0);
+            assert_close(ease(easing, 1.0), 1.0);
+        }
+    }
+
+    #[test]
+    fn easings_between_the_ends() {
+        assert_close(ease(Easing::Linear, 0.25), 0.25);
+        assert_close(ease(Easing::EaseIn, 0.25), 0.0625);
+        assert_close(ease(Easing::EaseOut, 0.25), 0.4375);
+        assert_close(ease(Easing::EaseInOut, 0.25), 0.125);
+        assert_close(ease(Easing::EaseInOut, 0.75), 0.875);
+        assert_close(ease(Easing::Cubic, 0.25), 0.0625);
+        assert_close(ease(Easing::Cubic, 0.75), 0.9375);
+        assert_close(ease(Easing::Sine, 0.5), 0.5);
+        assert_close(ease(Easing::Step, 0.99), 0.0);
+        // The first bounce lands at 1 / 2.75
+        assert_close(ease(Easing::Bounce, 1.0 / 2.75), 1.0);
+        // Elastic overshoots 1 before settling
+        assert!(ease(Easing::Elastic, 0.2) > 1.0);
+        // The symmetric easings are halfway at the middle
+        for &easing in &[Easing::Linear, Easing::EaseInOut, Easing::Cubic, Easing::Sine] {
+            assert_close(ease(easing, 0.5), 0.5);
+            assert_close(ease(easing, 0.3) + ease(easing, 0.7), 1.0);
+        }
+        // The rest (besides elastic) stay between 0 and 1
+        for &easing in &EASINGS[..8] {
+            for i in 0..101 {
+                let val = ease(easing, i as f64 / 100.0);
+                assert!(val >= 0.0 && val <= 1.0 + 1e-9, "{:?} at {} is {}", easing, i, val);
+            }
+        }
+    }
+
+    #[test]
+    fn vary() {
+        let script = "frames 20\nvary k 2 12 10 20\nvary e 0 10 0 1 ease-in";
+        assert_close(knobs_in(script, 2.0)["k"], 10.0);
+        assert_close(knobs_in(script, 7.0)["k"], 15.0);
+        assert_close(knobs_in(script, 12.0)["k"], 20.0);
+        assert_close(knobs_in(script, 5.0)["e"], 0.25);
+        assert!(!knobs_in(script, 1.0).contains_key("k"));
+        assert!(!knobs_in(script, 13.0).contains_key("k"));
+    }
+
+    #[test]
+    fn linear_keyframes() {
+        let track = keyframes(&[(0, 0.0), (4, 8.0), (10, 2.0)], Interpolation::Linear);
+        assert_close(keyframe_val(&track, 0.0).unwrap(), 0.0);
+        assert_close(keyframe_val(&track, 2.0).unwrap(), 4.0);
+        assert_close(keyframe_val(&track, 4.0).unwrap(), 8.0);
+        assert_close(keyframe_val(&track, 7.0).unwrap(), 5.0);
+        assert_close(keyframe_val(&track, 10.0).unwrap(), 2.0);
+        assert_close(keyframe_val(&track, 0.5).unwrap(), 1.0);
+        assert_eq!(keyframe_val(&track, 10.5), None);
+    }
+
+    #[test]
+    fn catmull_rom_keyframes() {
+        let track = keyframes(&[(0, 0.0), (4, 8.0), (10, 2.0)], Interpolation::CatmullRom);
+        // The curve passes through every key
+        for &(frame, val) in &track.keys {
+            assert_close(keyframe_val(&track, frame as f64).unwrap(), val);
+        }
+        // Slopes of 2 per frame at the first key and 0.2 at the second, so the middle of the first
+        // segment is 4 * (0.125 * 2) + 0.5 * 8 - 4 * (0.125 * 0.2)
+        assert_close(keyframe_val(&track, 2.0).unwrap(), 4.9);
+    }
+
+    #[test]
+    fn bezier_keyframes() {
+        let track = keyframes(&[(0, 0.0), (4, 8.0)], Interpolation::Bezier);
+        assert_close(keyframe_val(&track, 0.0).unwrap(), 0.0);
+        assert_close(keyframe_val(&track, 1.0).unwrap(), 1.25);
+        assert_close(keyframe_val(&track, 2.0).unwrap(), 4.0);
+        assert_close(keyframe_val(&track, 3.0).unwrap(), 6.75);
+        assert_close(keyframe_val(&track, 4.0).unwrap(), 8.0);
+    }
+
+    #[test]
+    fn single_keyframe() {
+        for &interpolation in &[Interpolation::Linear, Interpolation::CatmullRom, Interpolation::Bezier] {
+            let track = keyframes(&[(5, 3.0)], interpolation);
+            assert_eq!(keyframe_val(&track, 5.0), Some(3.0));
+            assert_eq!(keyframe_val(&track, 4.0), None);
+            assert_eq!(keyframe_val(&track, 6.0), None);
+            assert_eq!(keyframe_val(&track, 5.5), None);
+        }
+    }
+
+    #[test]
+    fn tween() {
+        let script = "frames 11\nset a 0\nset b 10\nsave_knobs p0\nset a 100\nset b 20\nsave_knobs p1\ntween 2 6 p0 p1";
+        assert_close(knobs_in(script, 2.0)["a"], 0.0);
+        assert_close(knobs_in(script, 4.0)["a"], 50.0);
+        assert_close(knobs_in(script, 4.0)["b"], 15.0);
+        assert_close(knobs_in(script, 6.0)["b"], 20.0);
+        assert_close(knobs_in(script, 5.5)["a"], 87.5);
+        assert!(!knobs_in(script, 7.0).contains_key("a"));
+    }
+
+    #[test]
+    fn keyframes_override_varies_which_override_tweens() {
+        let script = "frames 11\nset k 0\nsave_knobs p0\nset k 1\nsave_knobs p1\ntween 0 10 p0 p1\n\
+                      vary k 0 5 100 200\nkeyframes k 0:-1 2:-2";
+        assert_close(knobs_in(script, 1.0)["k"], -1.5);
+        assert_close(knobs_in(script, 4.0)["k"], 180.0);
+        assert_close(knobs_in(script, 8.0)["k"], 0.8);
+    }
+
+    #[test]
+    fn knobs_between_animations_keep_their_nearest_values() {
+        // Between the two varies, the knob has the value from the nearest frame
+        let script = "frames 10\nvary k 0 4 0 4\nvary k 5 9 10 14";
+        assert_close(knobs_in(script, 3.5)["k"], 3.5);
+        assert_close(knobs_in(script, 4.25)["k"], 4.0);
+        assert_close(knobs_in(script, 4.75)["k"], 10.0);
+    }
+
+    #[test]
+    fn sets_are_defaults_for_frames_without_tweens() {
+        let script = "frames 10\nset a 0\nset b 2\nsave_knobs k0\nset a 100\nsave_knobs k1\ntween 0 4 k0 k1\n\
+                      set c 7\nsetknobs 3";
+        let knobs = knobs_after(script, 2);
+        assert_close(knobs["a"], 50.0);
+        assert_close(knobs["b"], 2.0);
+        assert_close(knobs["c"], 3.0);
+        // After the tween, the knobs have the values they were last set to
+        let knobs = knobs_after(script, 5);
+        assert_close(knobs["a"], 3.0);
+        assert_close(knobs["b"], 3.0);
+        assert_close(knobs["c"], 3.0);
+    }
+
+    #[test]
+    fn knobs_set_only_before_saving_are_drawn_after_tweens() {
+        let script = "frames 10\nset a 0\nset b 2\nsave_knobs k0\nset a 100\nsave_knobs k1\ntween 0 4 k0 k1\n\
+                      move 1 1 1 b\nbox 0 0 0 10 10 10";
+        let cmds = parse::parse(script).unwrap();
+        let rendered = render(&cmds, 2, None, ".", Some((20, 20)), false, |_| {});
+        match rendered {
+            Ok(Rendered::Animation { rendered, .. }) => assert_eq!(rendered, (0, 9)),
+            _ => panic!("the animation was not rendered"),
+        }
+        assert_close(knobs_after(script, 5)["b"], 2.0);
+    }
+
+    #[test]
+    fn pop_with_nothing_pushed() {
+        let cmds = parse::parse("set n 3\npush\nrepeat n i\npop\nend\nbox 0 0 0 1 1 1").unwrap();
+        let rendered = render(&cmds, 1, None, ".", Some((20, 20)), false, |_| {});
+        assert!(rendered.is_err());
+    }
+
+    #[test]
+    fn repeats_have_a_limit() {
+        let cmds = parse::parse("set n 1e30\nrepeat n i\nbox 0 0 0 1 1 1\nend").unwrap();
+        let rendered = render(&cmds, 1, None, ".", Some((20, 20)), false, |_| {});
+        assert_eq!(rendered.err(), Some(String::from("Cannot repeat 1000000000000000000000000000000 times (the most is 1000000)")));
+    }
+}
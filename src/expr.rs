@@ -0,0 +1,488 @@
+use std::fmt;
+use std::f64::consts::PI;
+
+/// An arithmetic expression used as a numeric argument to a command, e.g. `(2 * i + 1) * 10`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr<'a> {
+    Num(f64),
+    Pi,
+    /// The number of the frame being rendered (0 for still images), which is between frame numbers
+    /// for the sub-frames of motion blur
+    Frame,
+    /// A parameter of the enclosing `def` block or the index of an enclosing `repeat` block
+    Var(&'a str),
+    Knob(&'a str),
+    Neg(Box<Expr<'a>>),
+    BinOp(Op, Box<Expr<'a>>, Box<Expr<'a>>),
+    Func(Func, Box<Expr<'a>>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Func {
+    Sin,
+    Cos,
+    Sqrt,
+}
+
+/// Where the names in an expression get their values from.
+pub trait Env<'a> {
+    fn var(&self, name: &'a str) -> Result<f64, String>;
+    fn knob(&self, name: &'a str) -> Result<f64, String>;
+    fn frame(&self) -> Result<f64, String>;
+}
+
+impl<'a> Expr<'a> {
+    pub fn eval<E: Env<'a>>(&self, env: &E) -> Result<f64, String> {
+        match *self {
+            Expr::Num(x) => Ok(x),
+            Expr::Pi => Ok(PI),
+            Expr::Frame => env.frame(),
+            Expr::Var(name) => env.var(name),
+            Expr::Knob(name) => env.knob(name),
+            Expr::Neg(ref e) => Ok(-e.eval(env)?),
+            Expr::BinOp(op, ref lhs, ref rhs) => {
+                let (lhs, rhs) = (lhs.eval(env)?, rhs.eval(env)?);
+                Ok(match op {
+                    Op::Add => lhs + rhs,
+                    Op::Sub => lhs - rhs,
+                    Op::Mul => lhs * rhs,
+                    Op::Div => lhs / rhs,
+                })
+            },
+            Expr::Func(func, ref arg) => {
+                let arg = arg.eval(env)?;
+                Ok(match func {
+                    Func::Sin => arg.sin(),
+                    Func::Cos => arg.cos(),
+                    Func::Sqrt => arg.sqrt(),
+                })
+            },
+        }
+    }
+
+    /// Add the names of the knobs the expression uses to `knobs`.
+    pub fn knobs(&self, knobs: &mut Vec<&'a str>) {
+        match *self {
+            Expr::Knob(name) => knobs.push(name),
+            Expr::Neg(ref e) | Expr::Func(_, ref e) => e.knobs(knobs),
+            Expr::BinOp(_, ref lhs, ref rhs) => {
+                lhs.knobs(knobs);
+                rhs.knobs(knobs);
+            },
+            Expr::Num(_) | Expr::Pi | Expr::Frame | Expr::Var(_) => {},
+        }
+    }
+
+    /// Get the value of the expression if it does not depend on any variables, knobs, or the
+    /// frame number.
+    pub fn constant_value(&self) -> Option<f64> {
+        self.eval(&NoNames).ok()
+    }
+}
+
+// How tightly each kind of expression binds, so that parentheses are only written where needed
+fn precedence(expr: &Expr) -> u8 {
+    match *expr {
+        Expr::BinOp(Op::Add, ..) | Expr::BinOp(Op::Sub, ..) => 1,
+        Expr::BinOp(Op::Mul, ..) | Expr::BinOp(Op::Div, ..) => 2,
+        Expr::Neg(_) => 3,
+        Expr::Num(x) if x.is_sign_negative() => 3,
+        _ => 4,
+    }
+}
+
+// Write `expr`, in parentheses if it binds less tightly than `min`
+fn write_operand(f: &mut fmt::Formatter, expr: &Expr, min: u8) -> fmt::Result {
+    if precedence(expr) < min {
+        write!(f, "({})", expr)
+    } else {
+        write!(f, "{}", expr)
+    }
+}
+
+/// Expressions are written without spaces, so that they are a single lexeme in a script.
+impl<'a> fmt::Display for Expr<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::Num(x) => write!(f, "{}", x),
+            Expr::Pi => write!(f, "pi"),
+            Expr::Frame => write!(f, "frame"),
+            Expr::Var(name) | Expr::Knob(name) => write!(f, "{}", name),
+            Expr::Neg(ref e) => {
+                write!(f, "-")?;
+                // A negative number after a minus sign would be read back as a positive number
+                write_operand(f, e, if precedence(e) == 3 { 4 } else { 3 })
+            },
+            Expr::BinOp(op, ref lhs, ref rhs) => {
+                let (prec, symbol) = match op {
+                    Op::Add => (1, '+'),
+                    Op::Sub => (1, '-'),
+                    Op::Mul => (2, '*'),
+                    Op::Div => (2, '/'),
+                };
+                write_operand(f, lhs, prec)?;
+                write!(f, "{}", symbol)?;
+                // Operators are left associative, and `a--1` is hard to read
+                write_operand(f, rhs, if precedence(rhs) == 3 { 4 } else { prec + 1 })
+            },
+            Expr::Func(func, ref arg) => {
+                let name = match func {
+                    Func::Sin => "sin",
+                    Func::Cos => "cos",
+                    Func::Sqrt => "sqrt",
+                };
+                write!(f, "{}({})", name, arg)
+            },
+        }
+    }
+}
+
+// An environment in which no names have values
+struct NoNames;
+
+impl<'a> Env<'a> for NoNames {
+    fn var(&self, name: &'a str) -> Result<f64, String> {
+        Err(format!("'{}' is not constant", name))
+    }
+
+    fn knob(&self, name: &'a str) -> Result<f64, String> {
+        Err(format!("'{}' is not constant", name))
+    }
+
+    fn frame(&self) -> Result<f64, String> {
+        Err(String::from("'frame' is not constant"))
+    }
+}
+
+/// An error in the syntax of an expression. `at` is the slice of the source at which the error
+/// was found, and is empty if the expression ended too early.
+#[derive(Debug)]
+pub struct SyntaxError<'a> {
+    pub at: &'a str,
+    pub expected: &'static str,
+}
+
+/// Parse `src` as an expression. Names in `vars` are variables, and all other names (besides
+/// `pi` and `frame`) are knobs.
+pub fn parse<'a>(src: &'a str, vars: &[&'a str]) -> Result<Expr<'a>, SyntaxError<'a>> {
+    let mut parser = Parser { rest: src, vars };
+    let expr = parser.sum()?;
+    parser.skip_whitespace();
+    if parser.rest.is_empty() {
+        Ok(expr)
+    } else {
+        Err(parser.error("an operator"))
+    }
+}
+
+// A recursive descent parser, with one method per level of precedence
+struct Parser<'a, 'v> {
+    rest: &'a str,
+    vars: &'v [&'a str],
+}
+
+impl<'a, 'v> Parser<'a, 'v> {
+    fn error(&self, expected: &'static str) -> SyntaxError<'a> {
+        let end = self.rest.find(char::is_whitespace).unwrap_or(self.rest.len());
+        SyntaxError { at: &self.rest[..end], expected }
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    // Consume `c` if it is the next character
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.rest.starts_with(c) {
+            self.rest = &self.rest[c.len_utf8()..];
+            true
+        } else {
+            false
+        }
+    }
+
+    // Consume the longest prefix whose characters satisfy `pred`
+    fn take_while<P: Fn(char) -> bool>(&mut self, pred: P) -> &'a str {
+        let end = self.rest.find(|c| !pred(c)).unwrap_or(self.rest.len());
+        let (taken, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        taken
+    }
+
+    fn sum(&mut self) -> Result<Expr<'a>, SyntaxError<'a>> {
+        let mut expr = self.product()?;
+        loop {
+            let op = if self.eat('+') {
+                Op::Add
+            } else if self.eat('-') {
+                Op::Sub
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::BinOp(op, Box::new(expr), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr<'a>, SyntaxError<'a>> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = if self.eat('*') {
+                Op::Mul
+            } else if self.eat('/') {
+                Op::Div
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::BinOp(op, Box::new(expr), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr<'a>, SyntaxError<'a>> {
+        if self.eat('-') {
+            let operand = self.unary()?;
+            // Keep negative literals as plain numbers
+            match operand {
+                Expr::Num(x) => Ok(Expr::Num(-x)),
+                other => Ok(Expr::Neg(Box::new(other))),
+            }
+        } else if self.eat('+') {
+            self.unary()
+        } else {
+            self.atom()
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expr<'a>, SyntaxError<'a>> {
+        self.skip_whitespace();
+        if self.eat('(') {
+            let expr = self.sum()?;
+            if self.eat(')') {
+                Ok(expr)
+            } else {
+                Err(self.error("')'"))
+            }
+        } else if self.rest.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+            self.number()
+        } else if self.rest.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+            self.name()
+        } else {
+            Err(self.error("a number, a name, or '('"))
+        }
+    }
+
+    fn number(&mut self) -> Result<Expr<'a>, SyntaxError<'a>> {
+        let before = self.rest;
+        let mut len = self.take_while(|c| c.is_ascii_digit() || c == '.').len();
+        // Allow exponents, as in 1e-3
+        if self.rest.starts_with(['e', 'E']) {
+            let after_e = &self.rest[1..];
+            let exponent = if after_e.starts_with(['+', '-']) { &after_e[1..] } else { after_e };
+            if exponent.starts_with(|c: char| c.is_ascii_digit()) {
+                self.rest = exponent;
+                self.take_while(|c| c.is_ascii_digit());
+                len = before.len() - self.rest.len();
+            }
+        }
+        let literal = &before[..len];
+        match literal.parse::<f64>() {
+            Ok(x) if x.is_finite() => Ok(Expr::Num(x)),
+            // Too big to be written back out as a number (e.g. 1e999)
+            Ok(_) => Err(SyntaxError { at: literal, expected: "a finite number" }),
+            Err(_) => Err(SyntaxError { at: literal, expected: "a number" }),
+        }
+    }
+
+    fn name(&mut self) -> Result<Expr<'a>, SyntaxError<'a>> {
+        let name = self.take_while(|c| c.is_alphanumeric() || c == '_');
+        // Names like `inf` and `NaN` were once read as numbers, so they are not knobs either
+        if name.parse::<f64>().is_ok() {
+            return Err(SyntaxError { at: name, expected: "a finite number" });
+        }
+        if self.eat('(') {
+            let func = match name {
+                "sin" => Func::Sin,
+                "cos" => Func::Cos,
+                "sqrt" => Func::Sqrt,
+                _ => return Err(SyntaxError { at: name, expected: "a function (sin, cos, or sqrt)" }),
+            };
+            let arg = self.sum()?;
+            if self.eat(')') {
+                Ok(Expr::Func(func, Box::new(arg)))
+            } else {
+                Err(self.error("')'"))
+            }
+        } else if self.vars.contains(&name) {
+            // Variables take precedence over the built-in names
+            Ok(Expr::Var(name))
+        } else if name == "pi" {
+            Ok(Expr::Pi)
+        } else if name == "frame" {
+            Ok(Expr::Frame)
+        } else {
+            Ok(Expr::Knob(name))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Variables, knobs, and the frame number, all with values
+    struct Names;
+
+    impl<'a> Env<'a> for Names {
+        fn var(&self, name: &'a str) -> Result<f64, String> {
+            match name {
+                "i" => Ok(3.0),
+                "pi" => Ok(10.0),
+                _ => Err(format!("Variable '{}' has no value", name)),
+            }
+        }
+
+        fn knob(&self, name: &'a str) -> Result<f64, String> {
+            match name {
+                "k" => Ok(0.5),
+                _ => Err(format!("Knob '{}' has no value", name)),
+            }
+        }
+
+        fn frame(&self) -> Result<f64, String> {
+            Ok(7.0)
+        }
+    }
+
+    fn eval(src: &str, vars: &[&str]) -> f64 {
+        parse(src, vars).unwrap().eval(&Names).unwrap()
+    }
+
+    fn num<'a>(x: f64) -> Box<Expr<'a>> {
+        Box::new(Expr::Num(x))
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(eval("1+2*3", &[]), 7.0);
+        assert_eq!(eval("(1+2)*3", &[]), 9.0);
+        assert_eq!(eval("10-4-3", &[]), 3.0);
+        assert_eq!(eval("12/3/2", &[]), 2.0);
+        assert_eq!(eval(" 2 * 3 + 4 * 5 ", &[]), 26.0);
+        assert_eq!(parse("1-2+3", &[]).unwrap(),
+                   Expr::BinOp(Op::Add, Box::new(Expr::BinOp(Op::Sub, num(1.0), num(2.0))), num(3.0)));
+    }
+
+    #[test]
+    fn unary_minus() {
+        // Negative literals are folded into the number
+        assert_eq!(parse("-2", &[]).unwrap(), Expr::Num(-2.0));
+        assert_eq!(parse("--2", &[]).unwrap(), Expr::Num(2.0));
+        assert_eq!(parse("+2", &[]).unwrap(), Expr::Num(2.0));
+        // But not into other expressions
+        assert_eq!(parse("-k", &[]).unwrap(), Expr::Neg(Box::new(Expr::Knob("k"))));
+        assert_eq!(parse("-(1+2)", &[]).unwrap(), Expr::Neg(Box::new(Expr::BinOp(Op::Add, num(1.0), num(2.0)))));
+        assert_eq!(eval("2*-3", &[]), -6.0);
+        assert_eq!(eval("2--3", &[]), 5.0);
+        assert_eq!(eval("-k*2", &[]), -1.0);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse("1.5", &[]).unwrap(), Expr::Num(1.5));
+        assert_eq!(parse(".5", &[]).unwrap(), Expr::Num(0.5));
+        assert_eq!(parse("1e-3", &[]).unwrap(), Expr::Num(0.001));
+        assert_eq!(parse("2E2", &[]).unwrap(), Expr::Num(200.0));
+        let e = parse("-1e999", &[]).unwrap_err();
+        assert_eq!(e.at, "1e999");
+        assert_eq!(e.expected, "a finite number");
+        // `e` without an exponent is a name after the number
+        assert_eq!(parse("2e", &[]).unwrap_err().at, "e");
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(eval("sqrt(16)", &[]), 4.0);
+        assert_eq!(eval("sin(0)+cos(0)", &[]), 1.0);
+        assert_eq!(eval("2*sqrt(k*8)", &[]), 4.0);
+        let e = parse("tan(1)", &[]).unwrap_err();
+        assert_eq!(e.at, "tan");
+        assert_eq!(e.expected, "a function (sin, cos, or sqrt)");
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(parse("pi", &[]).unwrap(), Expr::Pi);
+        assert_eq!(parse("frame", &[]).unwrap(), Expr::Frame);
+        assert_eq!(parse("i", &["i"]).unwrap(), Expr::Var("i"));
+        assert_eq!(parse("i", &[]).unwrap(), Expr::Knob("i"));
+        assert_eq!(eval("frame*2", &[]), 14.0);
+        // Variables shadow the built-in names
+        assert_eq!(parse("pi", &["pi"]).unwrap(), Expr::Var("pi"));
+        assert_eq!(parse("frame", &["frame"]).unwrap(), Expr::Var("frame"));
+        assert_eq!(eval("pi+i", &["pi", "i"]), 13.0);
+    }
+
+    #[test]
+    fn knobs_used() {
+        let mut knobs = vec![];
+        parse("a*sin(b)+-(c/2)+i+pi", &["i"]).unwrap().knobs(&mut knobs);
+        assert_eq!(knobs, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn constant_values() {
+        assert_eq!(parse("2*pi", &[]).unwrap().constant_value(), Some(2.0 * PI));
+        assert_eq!(parse("k+1", &[]).unwrap().constant_value(), None);
+        assert_eq!(parse("frame", &[]).unwrap().constant_value(), None);
+        assert_eq!(parse("i", &["i"]).unwrap().constant_value(), None);
+    }
+
+    #[test]
+    fn infinity_and_nan_are_not_names() {
+        for src in &["inf", "-inf", "NaN", "2*infinity", "nan+1"] {
+            let e = parse(src, &[]).unwrap_err();
+            assert_eq!(e.expected, "a finite number", "{}", src);
+        }
+        assert_eq!(parse("2*inf", &[]).unwrap_err().at, "inf");
+        // Longer names are fine
+        assert_eq!(parse("info", &[]).unwrap(), Expr::Knob("info"));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let e = parse("1+", &[]).unwrap_err();
+        assert_eq!(e.at, "");
+        assert_eq!(e.expected, "a number, a name, or '('");
+        let e = parse("(1+2", &[]).unwrap_err();
+        assert_eq!(e.at, "");
+        assert_eq!(e.expected, "')'");
+        let e = parse("1 2", &[]).unwrap_err();
+        assert_eq!(e.at, "2");
+        assert_eq!(e.expected, "an operator");
+        let e = parse("2*$x", &[]).unwrap_err();
+        assert_eq!(e.at, "$x");
+        let e = parse("1.2.3", &[]).unwrap_err();
+        assert_eq!(e.at, "1.2.3");
+        assert_eq!(e.expected, "a number");
+        let e = parse("sqrt(4", &[]).unwrap_err();
+        assert_eq!(e.expected, "')'");
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for src in &["1+2*3", "(1+2)*3", "1-(2-3)", "-k", "-(-k)", "2*-3", "2-(-3)", "a/(b*c)",
+                     "sin(frame*pi/2)", "-sqrt(2)", "i*2", "1e-7", "-(1+k)*2"] {
+            let expr = parse(src, &["i"]).unwrap();
+            let written = expr.to_string();
+            assert_eq!(parse(&written, &["i"]).unwrap(), expr, "{} was written as {}", src, written);
+        }
+    }
+}
@@ -0,0 +1,428 @@
+use std::fmt;
+
+use expr::Expr;
+use parse::{ Axis, Command, Easing, Interpolation, Projection };
+
+const INDENT: &str = "    ";
+
+/// A list of commands, displayed as the text of a script that parses back into the same list.
+/// Arguments are separated by single spaces, and the bodies of `def` and `repeat` blocks are
+/// indented. The bodies of `include` commands are not written, as they come from the included
+/// file, so scripts with includes only round-trip through `parse::parse_sources`.
+pub struct Script<'a: 'b, 'b>(pub &'b [Command<'a>]);
+
+impl<'a, 'b> fmt::Display for Script<'a, 'b> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_block(f, self.0, 0)
+    }
+}
+
+fn write_block(f: &mut fmt::Formatter, cmds: &[Command], depth: usize) -> fmt::Result {
+    for cmd in cmds {
+        for _ in 0..depth {
+            write!(f, "{}", INDENT)?;
+        }
+        write_command(f, cmd, depth)?;
+        writeln!(f)?;
+    }
+    Ok(())
+}
+
+fn write_command(f: &mut fmt::Formatter, cmd: &Command, depth: usize) -> fmt::Result {
+    match *cmd {
+        Command::Comment(text) => write!(f, "{}", text),
+        Command::Push => write!(f, "push"),
+        Command::Pop => write!(f, "pop"),
+        Command::Save(filename) => write!(f, "save {}", filename),
+        Command::Display => write!(f, "display"),
+        Command::Move { ref x, ref y, ref z, knob } => {
+            write!(f, "move {} {} {}", x, y, z)?;
+            write_opt(f, knob)
+        },
+        Command::Rotate(axis, ref degrees, knob) => {
+            let axis = match axis {
+                Axis::X => "x",
+                Axis::Y => "y",
+                Axis::Z => "z",
+            };
+            write!(f, "rotate {} {}", axis, degrees)?;
+            write_opt(f, knob)
+        },
+        Command::Scale { ref x, ref y, ref z, knob } => {
+            write!(f, "scale {} {} {}", x, y, z)?;
+            write_opt(f, knob)
+        },
+        Command::SaveCoordSystem(name) => write!(f, "save_coord_system {}", name),
+        Command::Box { ref x, ref y, ref z, ref w, ref h, ref d, constants, cs } => {
+            write!(f, "box")?;
+            write_solid_start(f, constants, x)?;
+            write!(f, " {} {} {} {} {}", y, z, w, h, d)?;
+            write_opt(f, cs)
+        },
+        Command::Sphere { ref x, ref y, ref z, ref r, constants, cs } => {
+            write!(f, "sphere")?;
+            write_solid_start(f, constants, x)?;
+            write!(f, " {} {} {}", y, z, r)?;
+            write_opt(f, cs)
+        },
+        Command::Torus { ref x, ref y, ref z, ref r0, ref r1, constants, cs } => {
+            write!(f, "torus")?;
+            write_solid_start(f, constants, x)?;
+            write!(f, " {} {} {} {}", y, z, r0, r1)?;
+            write_opt(f, cs)
+        },
+        Command::Line { ref x0, ref y0, ref z0, ref x1, ref y1, ref z1, cs } => {
+            write!(f, "line {} {} {} {} {} {}", x0, y0, z0, x1, y1, z1)?;
+            write_opt(f, cs)
+        },
+        Command::Circle { ref x, ref y, ref z, ref r, segments, knob, cs } => {
+            write!(f, "circle {} {} {} {}", x, y, z, r)?;
+            write_curve_end(f, segments, knob, cs)
+        },
+        Command::Bezier { ref p0, ref p1, ref p2, ref p3, segments, knob, cs } => {
+            write!(f, "bezier")?;
+            for p in &[p0, p1, p2, p3] {
+                write_point(f, p)?;
+            }
+            write_curve_end(f, segments, knob, cs)
+        },
+        Command::Hermite { ref p0, ref p1, ref m0, ref m1, segments, knob, cs } => {
+            write!(f, "hermite")?;
+            for p in &[p0, p1, m0, m1] {
+                write_point(f, p)?;
+            }
+            write_curve_end(f, segments, knob, cs)
+        },
+        Command::Frames(n) => write!(f, "frames {}", n),
+        Command::Resolution(width, height) => write!(f, "resolution {} {}", width, height),
+        Command::Basename(name) => write!(f, "basename {}", name),
+        Command::MotionBlur { samples, shutter } => write!(f, "motionblur {} {}", samples, shutter),
+        Command::Vary(ref v) => {
+            write!(f, "vary {} {} {} {} {}", v.knob, v.fst_frame, v.last_frame, v.min_val, v.max_val)?;
+            match v.easing {
+                Easing::Linear => Ok(()),
+                easing => write!(f, " {}", easing.name()),
+            }
+        },
+        Command::Keyframes(ref k) => {
+            write!(f, "keyframes {}", k.knob)?;
+            for &(frame, val) in &k.keys {
+                write!(f, " {}:{}", frame, val)?;
+            }
+            match k.interpolation {
+                Interpolation::Linear => Ok(()),
+                interpolation => write!(f, " {}", interpolation.name()),
+            }
+        },
+        Command::Set(knob, ref value) => write!(f, "set {} {}", knob, value),
+        Command::SetKnobs(ref value) => write!(f, "setknobs {}", value),
+        Command::SaveKnobs(name) => write!(f, "save_knobs {}", name),
+        Command::Tween { fst_frame, last_frame, from, to } => {
+            write!(f, "tween {} {} {} {}", fst_frame, last_frame, from, to)
+        },
+        Command::Ambient(ref r, ref g, ref b) => write!(f, "ambient {} {} {}", r, g, b),
+        Command::Light(ref r, ref g, ref b, ref x, ref y, ref z) => write!(f, "light {} {} {} {} {} {}", r, g, b, x, y, z),
+        Command::Camera { ref eye, ref aim, ref up, ref projection } => {
+            match *projection {
+                Projection::Perspective { .. } => write!(f, "camera")?,
+                Projection::Orthographic { .. } => write!(f, "ortho")?,
+            }
+            for p in &[eye, aim, up] {
+                write_point(f, p)?;
+            }
+            match *projection {
+                Projection::Perspective { ref fov } => write!(f, " {}", fov),
+                Projection::Orthographic { ref left, ref right, ref bottom, ref top, ref near, ref far } => {
+                    write!(f, " {} {} {} {} {} {}", left, right, bottom, top, near, far)
+                },
+            }
+        },
+        Command::Constants(name, ref material) => {
+            write!(f, "constants {}", name)?;
+            for c in &[&material.r, &material.g, &material.b] {
+                write!(f, " {} {} {}", c.ambient, c.diffuse, c.specular)?;
+            }
+            match material.intensity {
+                Some((r, g, b)) => write!(f, " {} {} {}", r, g, b),
+                None => Ok(()),
+            }
+        },
+        Command::Def { name, ref params, ref body } => {
+            write!(f, "def {}", name)?;
+            for param in params {
+                write!(f, " {}", param)?;
+            }
+            writeln!(f)?;
+            write_end(f, body, depth)
+        },
+        Command::Call(name, ref args) => {
+            write!(f, "call {}", name)?;
+            for arg in args {
+                write!(f, " {}", arg)?;
+            }
+            Ok(())
+        },
+        Command::Repeat { ref count, var, ref body } => {
+            writeln!(f, "repeat {} {}", count, var)?;
+            write_end(f, body, depth)
+        },
+        Command::Include { path, .. } => write!(f, "include {}", path),
+    }
+}
+
+// Write an optional trailing argument
+fn write_opt<T: fmt::Display>(f: &mut fmt::Formatter, arg: Option<T>) -> fmt::Result {
+    match arg {
+        Some(arg) => write!(f, " {}", arg),
+        None => Ok(()),
+    }
+}
+
+// Write the optional arguments at the end of a curve command
+fn write_curve_end(f: &mut fmt::Formatter, segments: Option<usize>, knob: Option<&str>, cs: Option<&str>) -> fmt::Result {
+    write_opt(f, segments)?;
+    write_opt(f, knob)?;
+    write_opt(f, cs)
+}
+
+fn write_point(f: &mut fmt::Formatter, p: &[Expr; 3]) -> fmt::Result {
+    write!(f, " {} {} {}", p[0], p[1], p[2])
+}
+
+// Write the optional constants name and the first numeric argument of a solid. Without a constants
+// name, a first argument that looks like a name must be in parentheses to be read as a number.
+fn write_solid_start(f: &mut fmt::Formatter, constants: Option<&str>, first: &Expr) -> fmt::Result {
+    write_opt(f, constants)?;
+    let first = first.to_string();
+    if constants.is_none() && first.starts_with(|c: char| c.is_alphabetic() || c == '_') && !first.contains('(') {
+        write!(f, " ({})", first)
+    } else {
+        write!(f, " {}", first)
+    }
+}
+
+// Write the body of a block, and the `end` that closes it
+fn write_end(f: &mut fmt::Formatter, body: &[Command], depth: usize) -> fmt::Result {
+    write_block(f, body, depth + 1)?;
+    for _ in 0..depth {
+        write!(f, "{}", INDENT)?;
+    }
+    write!(f, "end")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use parse;
+    use source::Sources;
+
+    // Every kind of command, with and without its optional arguments
+    const SCRIPT: &str = "\
+// A comment
+# Another comment
+frames 10
+resolution 400 300
+basename test
+motionblur 4 0.5
+vary spin 0 9 0 1
+vary grow 0 9 0.5 2 bounce
+keyframes k 0:0 5:1.5 9:-2
+keyframes j 0:1 9:2 catmull-rom
+set a 1
+set b (a*2)
+setknobs 0.5
+save_knobs start
+set a 2
+save_knobs end
+tween 0 9 start end
+constants shiny 0.1 0.2 0.3 0.4 0.5 0.6 0.7 0.8 0.9
+constants glow 0.1 0.2 0.3 0.4 0.5 0.6 0.7 0.8 0.9 255 128 0
+ambient 50 50 50
+light 255 255 255 1 1 1
+light (100*grow) 0 0 -1 0 0
+camera 0 0 500 0 0 0 0 1 0 60
+ortho 0 0 500 0 0 0 0 1 0 -250 250 -250 250 1 1000
+push
+move 250 250 0
+move 10 0 0 spin
+rotate x 30
+rotate y 360 spin
+rotate z -45 k
+scale 2 2 2
+scale 1 1 1 grow
+save_coord_system c
+box 0 0 0 10 20 30
+box shiny -10 (frame*2) 0 10 20 30 c
+box (a) 0 0 10 20 30
+sphere 0 0 0 50
+sphere glow 0 0 0 50 c
+torus 0 0 0 10 50
+torus shiny 0 0 0 10 50 c
+line 0 0 0 100 100 100
+line 0 0 0 100 100 100 c
+circle 0 0 0 50
+circle 0 0 0 50 40 spin c
+circle 0 0 0 50 c
+circle 0 0 0 50 spin
+bezier 0 0 0 10 50 0 50 10 0 60 60 0
+bezier 0 0 0 10 50 0 50 10 0 60 60 0 20 k
+hermite 0 0 0 10 10 0 50 0 0 0 50 0 spin c
+def petal size angle
+    rotate z angle
+    box 0 0 0 size size size
+end
+repeat 6 i
+    call petal (10+i) (i*60)
+end
+include part.mdl
+pop
+display
+save out.png
+";
+
+    // All of the kinds of commands named by `kind`
+    const KINDS: [&str; 34] = [
+        "comment", "push", "pop", "save", "display", "move", "rotate", "scale", "save_coord_system",
+        "box", "sphere", "torus", "line", "circle", "bezier", "hermite", "frames", "resolution",
+        "basename", "motionblur", "vary", "keyframes", "set", "setknobs", "save_knobs", "tween",
+        "ambient", "light", "camera", "constants", "def", "call", "repeat", "include",
+    ];
+
+    // The kind of a command. There is deliberately no catch-all arm, so that new kinds of commands
+    // must be added here, to `KINDS`, and to `SCRIPT`.
+    fn kind(cmd: &Command) -> &'static str {
+        match *cmd {
+            Command::Comment(..) => "comment",
+            Command::Push => "push",
+            Command::Pop => "pop",
+            Command::Save(..) => "save",
+            Command::Display => "display",
+            Command::Move { .. } => "move",
+            Command::Rotate(..) => "rotate",
+            Command::Scale { .. } => "scale",
+            Command::SaveCoordSystem(..) => "save_coord_system",
+            Command::Box { .. } => "box",
+            Command::Sphere { .. } => "sphere",
+            Command::Torus { .. } => "torus",
+            Command::Line { .. } => "line",
+            Command::Circle { .. } => "circle",
+            Command::Bezier { .. } => "bezier",
+            Command::Hermite { .. } => "hermite",
+            Command::Frames(..) => "frames",
+            Command::Resolution(..) => "resolution",
+            Command::Basename(..) => "basename",
+            Command::MotionBlur { .. } => "motionblur",
+            Command::Vary(..) => "vary",
+            Command::Keyframes(..) => "keyframes",
+            Command::Set(..) => "set",
+            Command::SetKnobs(..) => "setknobs",
+            Command::SaveKnobs(..) => "save_knobs",
+            Command::Tween { .. } => "tween",
+            Command::Ambient(..) => "ambient",
+            Command::Light(..) => "light",
+            Command::Camera { .. } => "camera",
+            Command::Constants(..) => "constants",
+            Command::Def { .. } => "def",
+            Command::Call(..) => "call",
+            Command::Repeat { .. } => "repeat",
+            Command::Include { .. } => "include",
+        }
+    }
+
+    // Add the kinds of `cmds` and of the commands in their bodies to `kinds`
+    fn kinds(cmds: &[Command], kinds: &mut HashSet<&'static str>) {
+        for cmd in cmds {
+            kinds.insert(kind(cmd));
+            match *cmd {
+                Command::Def { ref body, .. } | Command::Repeat { ref body, .. } | Command::Include { ref body, .. } => {
+                    self::kinds(body, kinds)
+                },
+                _ => {},
+            }
+        }
+    }
+
+    // Write the files `files` (paths and texts) in a new directory, giving the path of the first
+    fn write_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("graphics-format-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for &(path, text) in files {
+            fs::write(dir.join(path), text).unwrap();
+        }
+        dir.join(files[0].0)
+    }
+
+    // Parse `script`, write the commands back out, and check that the text parses back into the
+    // same commands (and is written out the same way again)
+    fn assert_round_trips(script: &str) {
+        let cmds = parse::parse(script).unwrap_or_else(|e| panic!("{:?}", e));
+        let written = Script(&cmds).to_string();
+        let reparsed = parse::parse(&written).unwrap_or_else(|e| panic!("{} could not be parsed: {:?}", written, e));
+        assert_eq!(reparsed, cmds, "{} was written as {}", script, written);
+        assert_eq!(Script(&reparsed).to_string(), written);
+    }
+
+    #[test]
+    fn every_command_round_trips() {
+        let path = write_files("every", &[("script", SCRIPT), ("part.mdl", "sphere 0 0 0 10\n")]);
+        let sources = Sources::from_text(&path, SCRIPT.to_string()).unwrap();
+        let (cmds, _) = parse::parse_sources(&sources).unwrap_or_else(|e| panic!("{:?}", e));
+
+        let mut seen = HashSet::new();
+        kinds(&cmds, &mut seen);
+        let all: HashSet<&str> = KINDS.iter().cloned().collect();
+        assert_eq!(seen, all);
+
+        let written = Script(&cmds).to_string();
+        let sources = Sources::from_text(&path, written.clone()).unwrap();
+        let (reparsed, _) = parse::parse_sources(&sources).unwrap_or_else(|e| panic!("{}\n{:?}", written, e));
+        assert_eq!(reparsed, cmds, "written as:\n{}", written);
+        assert_eq!(Script(&reparsed).to_string(), written);
+    }
+
+    #[test]
+    fn numbers_round_trip() {
+        assert_round_trips("vary k 0 10 0.1 1e300\nvary k 0 10 -0.000001 123456789.123 sine\n");
+        assert_round_trips("keyframes k 0:0.3 4:-1e-7 10:2.5e10 bezier\n");
+        assert_round_trips("move 1e-7 123456789.123 -0.5\nmove (1/3) -(2*-3) 1e300\n");
+        assert_round_trips("constants c 0.1 0.2 0.3 (1/3) 0.5 0.6 0.7 0.8 0.9 -1 0 1e-3\n");
+        assert_round_trips("frames 2\nmotionblur 3 (1/3)\n");
+    }
+
+    #[test]
+    fn optional_names_round_trip() {
+        assert_round_trips("box (a) 0 0 1 1 1\nbox c (a) 0 0 1 1 1\nsphere (pi) 0 0 1\ntorus frame 0 0 1 2\n");
+        assert_round_trips("save_coord_system c\ncircle 0 0 0 1 c\ncircle 0 0 0 1 k\ncircle 0 0 0 1 3 k c\n");
+        assert_round_trips("def f x\n    box (x) 0 0 1 1 1\n    repeat x i\n        move i 0 0 i\n    end\nend\ncall f 3\n");
+    }
+
+    #[test]
+    fn non_finite_numbers_are_rejected() {
+        for script in &["vary k 0 10 -1e999 2 bounce", "vary k 0 10 0 (1/0)", "keyframes k 0:1e999",
+                        "keyframes k 0:1 5:(0/0)", "constants c 1 1 1 1 1 1 1 1 (1/0)", "move 1e999 0 0",
+                        "frames 2\nmotionblur 2 (-1/0)"] {
+            let errors = parse::parse(script).err().unwrap_or_else(|| panic!("{} was parsed", script));
+            assert_eq!(errors.len(), 1, "{}", script);
+        }
+        let errors = parse::parse("vary k 0 10 -1e999 2 bounce").err().unwrap();
+        assert_eq!(errors[0].to_string(), "line 1, column 14: expected a finite number, found '1e999'");
+    }
+
+    #[test]
+    fn extra_input_is_rejected() {
+        let error = |script: &str| parse::parse(script).err().unwrap_or_else(|| panic!("{} was parsed", script))[0].to_string();
+        assert_eq!(error("move 1 2 3 k extra junk"), "line 1, column 14: expected end of line, found 'extra'");
+        assert_eq!(error("push push"), "line 1, column 6: expected end of line, found 'push'");
+        assert_eq!(error("box 0 0 0 10 10 10 20"), "line 1, column 20: expected end of line, found '20'");
+        assert_eq!(error("repeat 2 i j\nend"), "line 1, column 12: expected end of line, found 'j'");
+        assert_eq!(error("circle 0 0 0 1 3 k c 4"), "line 1, column 22: expected end of line, found '4'");
+        // Comments may still end a line
+        assert!(parse::parse("move 1 2 3 k // extra\npush # more").is_ok());
+    }
+}
@@ -0,0 +1,57 @@
+//! A graphics engine that renders scripts of drawing commands to images and animations.
+//!
+//! Scripts are loaded into a `scene::Scene` and drawn into `render::Screen`s by a
+//! `scene::Renderer`. The modules it is built from can also be used on their own, e.g. to draw
+//! `solid`s and `curve`s into an edge `matrix::Matrix` and render it.
+
+/// Matrix math
+pub mod matrix;
+
+/// Add curves to an edge matrix
+pub mod curve;
+
+/// Add 3D solids to an edge matrix
+pub mod solid;
+
+/// Render edges to an in-memory representation of the pixels of the screen
+pub mod render;
+
+/// Create image files
+pub mod ppm;
+
+/// Parse scripts into commands
+pub mod parse;
+
+/// Arithmetic expressions in script arguments
+pub mod expr;
+
+/// Load scripts and the files they include
+pub mod source;
+
+/// Write commands back out as script text
+pub mod format;
+
+/// Find problems in scripts before rendering them
+pub mod check;
+
+/// Execute commands from a script
+pub mod exec;
+
+/// Render scripts into memory
+pub mod scene;
+
+/// Remember which frames have not changed since they were saved
+pub mod cache;
+
+/// Save frames on several threads
+pub mod worker;
+
+/// Wait for scripts to change
+pub mod watch;
+
+/// Crate-wide constants and settings
+pub mod consts;
+
+pub use matrix::Matrix;
+pub use render::{ Screen, ZBuffer };
+pub use scene::{ Renderer, Scene };
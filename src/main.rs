@@ -1,92 +1,348 @@
-/// Matrix math
-mod matrix;
+extern crate graphics;
 
-/// Add curves to an edge matrix
-mod curve;
+use std::collections::HashSet;
+use std::env;
+use std::io::{ self, Read };
+use std::path::PathBuf;
+use std::process;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{ Duration, Instant };
 
-/// Add 3D solids to an edge matrix
-mod solid;
+use graphics::{ ppm, Renderer, Scene };
+use graphics::cache::FrameCache;
+use graphics::exec::Rendered;
+use graphics::watch::Watcher;
+use graphics::consts::*;
 
-/// Render edges to an in-memory representation of the pixels of the screen
-mod render;
+const USAGE: &str = "\
+Usage: main [OPTIONS] [SCRIPT]
 
-/// Create image files
-mod ppm;
+Render the script in the file SCRIPT (./script by default), or read from standard input if
+SCRIPT is -.
 
-mod parse;
+Options:
+  -o, --output DIR     Save the frames and GIFs of animations in DIR (default: anim)
+  -t, --threads N      Render the frames of animations on N threads (default: 4)
+  -w, --workers N      Save and convert frames on N threads (default: 8)
+  -f, --frames A-B     Only render frames A to B of an animation (or just frame A)
+  -r, --resolution WxH Render images W by H pixels (default: as given by the script's
+                       'resolution' command, or 500x500)
+      --force          Render every frame of an animation, even those already saved in
+                       the output directory that would look the same
+      --check          Only look for problems in the script, without rendering it
+      --watch          Render (or check) the script again whenever it or a file it
+                       includes changes, until stopped with Ctrl-C. Use --frames to
+                       only render a preview frame of an animation.
+      --debug          Print how long each frame takes to render, save, and convert
+  -h, --help           Print this message
 
-/// Execute commands from a script
-mod exec;
+Exits with status 1 if the script has errors, and 2 if the options are invalid.";
 
-mod worker;
+// Exit statuses
+const SCRIPT_ERROR: i32 = 1;
+const USAGE_ERROR: i32 = 2;
 
-/// Crate-wide constants
-mod consts;
+// How often to check whether the script has changed, with --watch
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
 
-use std::fs::File;
+/// What to do, as given by the command-line arguments
+struct Options {
+    /// The path of the script, or None to read it from standard input
+    script: Option<String>,
+    output_dir: String,
+    threads: usize,
+    workers: usize,
+    frames: Option<(usize, usize)>,
+    size: Option<(usize, usize)>,
+    force: bool,
+    check: bool,
+    watch: bool,
+    debug: bool,
+    help: bool,
+}
 
-use std::io::prelude::*;
-use std::sync::mpsc::channel;
-use std::time::Instant;
+fn main() {
+    let options = match parse_args(env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(msg) => usage_error(&msg),
+    };
+    if options.help {
+        println!("{}", USAGE);
+        return;
+    }
+    set_debug(options.debug);
 
-use consts::*;
+    if options.watch {
+        match options.script {
+            Some(ref path) => watch(path, &options),
+            None => usage_error("'--watch' needs a script file to watch, not standard input"),
+        }
+    }
 
-fn main() {
-    match File::open("script") {
-        Err(e) => {
-            panic!("Could not open file 'script'. Error: {}", e);
+    let scene = match options.script {
+        Some(ref path) => Scene::open(path),
+        None => {
+            let mut text = String::new();
+            match io::stdin().read_to_string(&mut text) {
+                // Included files are found relative to the current directory
+                Ok(_) => Scene::from_text("<stdin>", text),
+                Err(e) => Err(vec![format!("Could not read the script from standard input: {}", e)]),
+            }
         },
-        Ok(mut file) => {
-            let mut s = String::from("");
-            match file.read_to_string(&mut s) {
-                Ok(_) => {
-                    // `tx` is sent to run_script, which generates frames (on the heap) and
-                    // sends them to `tx`. `rx` is given to spawn_saver which duplicates it
-                    // into several worker threads for saving frames to a file.
-                    let (tx, rx) = channel();
-                    let handle = ppm::spawn_saver(rx);
-                    println!("Using {} worker threads to save and convert frames", NUM_WORKERS);
-
-                    // Make `anim` directory unless it exists
-                    ppm::mkdirp("anim");
-
-                    // Generate frames:
-                    let start = Instant::now();
-
-                    // frame_info, if Some, is a pair of the number of frames and the basename, and
-                    // is used to delete intermediate files (e.g. .ppm files) at the end.
-                    let frame_info: Option<(usize, &str)>;
-                    match exec::run_script(&s, tx) {
-                        Err(msg) => {
-                            println!("Error!\n{}", msg);
-                            frame_info = None;
-                        },
-                        Ok(opt_frame_info) => {
-                            frame_info = opt_frame_info;
-                            let elapsed = start.elapsed();
-                            println!("Time to generate frames in-memory: {} (includes some time saving images)", display_duration(elapsed));
-                        }
-                    }
-                    // Wait for worker threads to finish saving images
-                    handle.join();
+    };
+    let scene = match scene {
+        Ok(scene) => scene,
+        Err(errors) => {
+            print_errors(&errors);
+            process::exit(SCRIPT_ERROR);
+        },
+    };
+
+    // With --check, only look for problems in the script instead of rendering it
+    let ok = if options.check { check(&scene) } else { render(&scene, &options) };
+    if !ok {
+        process::exit(SCRIPT_ERROR);
+    }
+}
 
-                    let elapsed = start.elapsed();
-                    println!("Elapsed time, after generating frames and converting to PNGs: {}", display_duration(elapsed));
+fn usage_error(msg: &str) -> ! {
+    eprintln!("Error: {}\nRun with --help for usage", msg);
+    process::exit(USAGE_ERROR);
+}
 
-                    // If (multiple) frames were successfully generated, make a GIF and delete the rubbish
-                    if let Some((frames, basename)) = frame_info {
-                        ppm::convert_gif(frames, basename);
-                        ppm::clean_up_anim_ppms(frames, basename);
-                    }
+fn print_errors(errors: &[String]) {
+    println!("Error!");
+    for e in errors {
+        println!("{}", e);
+    }
+}
+
+// Print the problems in `scene`, returning whether there were none
+fn check(scene: &Scene) -> bool {
+    let problems = scene.check();
+    if problems.is_empty() {
+        println!("No problems found");
+        return true;
+    }
+    for problem in problems {
+        println!("{}", problem);
+    }
+    false
+}
+
+// Render `scene` and save its frames and GIF, returning whether it rendered without errors
+fn render(scene: &Scene, options: &Options) -> bool {
+    // Frames are sent to `tx` as they are rendered. `rx` is given to spawn_saver which duplicates
+    // it into several worker threads for saving frames to a file.
+    let (tx, rx) = channel();
+    let handle = ppm::spawn_saver(rx, options.workers);
+    println!("Using {} threads to render frames and {} worker threads to save and convert them", options.threads, options.workers);
+
+    // Make the output directory unless it exists
+    ppm::mkdirp(&options.output_dir);
 
-                    let elapsed_after_cleanup = start.elapsed();
-                    println!("Elapsed time, after cleaning up and converting to GIF: {}", display_duration(elapsed_after_cleanup));
+    for warning in scene.warnings() {
+        println!("WARNING: {}", warning);
+    }
+
+    // Generate frames:
+    let start = Instant::now();
+
+    // frame_info, if Some, is the number of frames and the basename, and is used to delete
+    // intermediate files (e.g. .ppm files) at the end.
+    let frame_info: Option<(usize, &str)>;
+    // The number, filename, and hash of each frame sent to be saved. Those which are saved are
+    // remembered in the frame cache.
+    let mut sent = vec![];
+    // Frames which haven't changed since they were last saved are skipped unless --force is given
+    let mut renderer = Renderer::new().threads(options.threads).output_dir(&options.output_dir)
+        .skip_unchanged(!options.force);
+    if let Some((first, last)) = options.frames {
+        renderer = renderer.frames(first, last);
+    }
+    if let Some((width, height)) = options.size {
+        renderer = renderer.resolution(width, height);
+    }
+    let result = renderer.render_each(scene, |frame| {
+        sent.push((frame.number, frame.filename.clone(), frame.hash));
+        // If the workers have stopped, the frame is not saved, and so not remembered either
+        let _ = tx.send((frame.filename, frame.screen));
+    });
+    let mut ok = result.is_ok();
+    match result {
+        Err(msg) => {
+            println!("Error!\n{}", msg);
+            frame_info = None;
+        },
+        Ok(rendered) => {
+            frame_info = match rendered {
+                Rendered::Animation { frames, basename, skipped, .. } => {
+                    if skipped > 0 {
+                        println!("Skipped {} frames which have not changed since they were saved (use --force to render them anyway)", skipped);
+                    }
+                    Some((frames, basename))
                 },
-                Err(e) => {
-                    panic!("Error reading text in ./script: {}", e);
-                }
+                // Still images are saved by the script's `save` commands
+                Rendered::Still(_) => None,
+            };
+            let elapsed = start.elapsed();
+            println!("Time to generate frames in-memory: {} (includes some time saving images)", display_duration(elapsed));
+        }
+    }
+    // Let the workers finish once every frame is sent
+    drop(tx);
+    // Wait for worker threads to finish saving images
+    let saved: HashSet<String> = match handle.join() {
+        Ok(saved) => saved.into_iter().collect(),
+        Err(_) => {
+            println!("A worker thread failed while saving frames");
+            ok = false;
+            HashSet::new()
+        },
+    };
+    // Frames which were rendered but could not be saved are failures too (each has been reported
+    // by the worker that tried to save it)
+    if sent.iter().any(|(_, filename, _)| !saved.contains(filename)) {
+        ok = false;
+    }
+
+    let elapsed = start.elapsed();
+    println!("Elapsed time, after generating frames and converting to PNGs: {}", display_duration(elapsed));
+
+    // If (multiple) frames were successfully generated, make a GIF and delete the rubbish. The GIF
+    // also has any frames not rendered this time that were saved before.
+    if let Some((frames, basename)) = frame_info {
+        ppm::convert_gif(&options.output_dir, frames, basename);
+        let numbers: Vec<usize> = sent.iter().map(|&(number, _, _)| number).collect();
+        ppm::clean_up_anim_ppms(&options.output_dir, frames, basename, &numbers);
+    }
+
+    // Remember what the saved frames were rendered from, so they can be skipped next time. Frames
+    // saved before an error are remembered too, but not those which could not be written.
+    if !saved.is_empty() {
+        let mut cache = FrameCache::load(&options.output_dir);
+        for (_, filename, hash) in sent {
+            if saved.contains(&filename) {
+                cache.insert(filename, hash);
             }
         }
+        if let Err(e) = cache.save() {
+            println!("Could not save the frame cache in '{}': {}", options.output_dir, e);
+        }
+    }
+
+    let elapsed_after_cleanup = start.elapsed();
+    println!("Elapsed time, after cleaning up and converting to GIF: {}", display_duration(elapsed_after_cleanup));
+    ok
+}
+
+// Render (or check) the script at `path`, and again whenever it or a file it includes changes,
+// until the program is stopped
+fn watch(path: &str, options: &Options) -> ! {
+    let mut watcher = Watcher::new();
+    loop {
+        match Scene::open(path) {
+            Ok(scene) => {
+                watcher.watch(&scene.sources().paths());
+                if options.check { check(&scene) } else { render(&scene, options) };
+            },
+            Err(errors) => {
+                // Keep watching the files included the last time the script was loaded, as one
+                // of them may be what needs fixing
+                let mut paths: Vec<PathBuf> = watcher.paths().iter().map(|p| p.to_path_buf()).collect();
+                if paths.is_empty() {
+                    paths.push(PathBuf::from(path));
+                }
+                watcher.watch(&paths);
+                print_errors(&errors);
+            },
+        }
+        println!("Watching {} for changes (press Ctrl-C to stop)", path);
+        watcher.wait(WATCH_INTERVAL);
+        // Give editors a moment to finish writing the file
+        thread::sleep(WATCH_INTERVAL);
+        println!();
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options {
+        script: Some(String::from("script")),
+        output_dir: String::from("anim"),
+        threads: NUM_RENDERERS,
+        workers: NUM_WORKERS,
+        frames: None,
+        size: None,
+        force: false,
+        check: false,
+        watch: false,
+        debug: false,
+        help: false,
+    };
+    let mut script = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "-h" | "--help" => options.help = true,
+            "--force" => options.force = true,
+            "--check" => options.check = true,
+            "--watch" => options.watch = true,
+            "--debug" => options.debug = true,
+            "-o" | "--output" => options.output_dir = option_value(&arg, args.next())?,
+            "-t" | "--threads" => options.threads = count(&arg, &option_value(&arg, args.next())?)?,
+            "-w" | "--workers" => options.workers = count(&arg, &option_value(&arg, args.next())?)?,
+            "-f" | "--frames" => options.frames = Some(frame_range(&option_value(&arg, args.next())?)?),
+            "-r" | "--resolution" => options.size = Some(resolution(&option_value(&arg, args.next())?)?),
+            _ if arg.starts_with('-') && arg != "-" => return Err(format!("Unknown option '{}'", arg)),
+            _ => {
+                if script.is_some() {
+                    return Err(format!("Only one script can be rendered, but '{}' was also given", arg));
+                }
+                script = Some(arg);
+            },
+        }
+    }
+    match script {
+        Some(ref path) if path == "-" => options.script = None,
+        Some(path) => options.script = Some(path),
+        None => {},
+    }
+    Ok(options)
+}
+
+// The argument following an option
+fn option_value(option: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("'{}' needs a value", option))
+}
+
+fn count(option: &str, value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("'{}' needs a positive whole number, not '{}'", option, value)),
+    }
+}
+
+// Parse a range of frames, e.g. "120-140", or a single frame, e.g. "7"
+fn frame_range(value: &str) -> Result<(usize, usize), String> {
+    let mut ends = value.splitn(2, '-').map(|n| n.parse::<usize>());
+    let range = match (ends.next(), ends.next()) {
+        (Some(Ok(first)), Some(Ok(last))) => (first, last),
+        (Some(Ok(frame)), None) => (frame, frame),
+        _ => return Err(format!("'--frames' needs a frame number or range like 120-140, not '{}'", value)),
+    };
+    if range.1 < range.0 {
+        return Err(format!("The range of frames '{}' ends before it starts", value));
+    }
+    Ok(range)
+}
+
+// Parse a width and height, e.g. "1920x1080"
+fn resolution(value: &str) -> Result<(usize, usize), String> {
+    let mut sides = value.splitn(2, 'x').map(|n| n.parse::<usize>());
+    match (sides.next(), sides.next()) {
+        (Some(Ok(width)), Some(Ok(height))) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err(format!("'--resolution' needs a width and height like 1920x1080, not '{}'", value)),
     }
 }
 
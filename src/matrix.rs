@@ -126,6 +126,45 @@ impl Matrix {
             0.0, 0.0, 0.0, 1.0)
     }
 
+    /// Make a 4x4 view matrix for a camera at `eye` looking towards `aim`, with `up` pointing
+    /// up. It moves the eye to the origin, looking down the -z axis with +y up. Returns None if
+    /// `eye` and `aim` are the same point or `up` is parallel to the direction looked in.
+    pub fn look_at(eye: [f64; 3], aim: [f64; 3], up: [f64; 3]) -> Option<Matrix> {
+        // The camera's axes: `right` is +x, `up` is +y, and `back` (away from `aim`) is +z
+        let back = normalize(sub3(eye, aim))?;
+        let right = normalize(cross(up, back))?;
+        let up = cross(back, right);
+        Some(Matrix::new4x4(
+            right[0], right[1], right[2], -dot3(right, eye),
+            up[0], up[1], up[2], -dot3(up, eye),
+            back[0], back[1], back[2], -dot3(back, eye),
+            0.0, 0.0, 0.0, 1.0))
+    }
+
+    /// Make a 4x4 perspective projection matrix with a vertical field of view of `fov` radians,
+    /// for a screen `aspect` times as wide as it is tall. Points between `near` and `far` in
+    /// front of the camera (looking down -z) are mapped to z from -1 to 1 after dividing by the
+    /// w coordinate, which is the distance in front of the camera.
+    pub fn perspective(fov: f64, aspect: f64, near: f64, far: f64) -> Matrix {
+        let f = 1.0 / f64::tan(fov / 2.0);
+        Matrix::new4x4(
+            f / aspect, 0.0, 0.0, 0.0,
+            0.0, f, 0.0, 0.0,
+            0.0, 0.0, (far + near) / (near - far), 2.0 * far * near / (near - far),
+            0.0, 0.0, -1.0, 0.0)
+    }
+
+    /// Make a 4x4 orthographic projection matrix, mapping the box from `left` to `right`,
+    /// `bottom` to `top`, and `near` to `far` in front of the camera (looking down -z) to the
+    /// cube from -1 to 1 in x, y, and z.
+    pub fn orthographic(left: f64, right: f64, bottom: f64, top: f64, near: f64, far: f64) -> Matrix {
+        Matrix::new4x4(
+            2.0 / (right - left), 0.0, 0.0, -(right + left) / (right - left),
+            0.0, 2.0 / (top - bottom), 0.0, -(top + bottom) / (top - bottom),
+            0.0, 0.0, -2.0 / (far - near), -(far + near) / (far - near),
+            0.0, 0.0, 0.0, 1.0)
+    }
+
     /// Get an array of the elements in column `colnum`.
     pub fn col(&self, colnum: usize) -> [f64; 4] {
         if colnum > self.cols.len() {
@@ -233,6 +272,15 @@ impl Matrix {
         }
     }
 
+    /// Get the product `self` * `point`, where `point` is a single column
+    pub fn transform_point(&self, point: [f64; 4]) -> [f64; 4] {
+        let mut col = [0.0f64; 4];
+        for (i, val) in col.iter_mut().enumerate() {
+            *val = dot_product(self.row_iter(i), point.iter().cloned());
+        }
+        col
+    }
+
     /// Perform the matrix product `self` * `rhs`, overwriting `self`
     pub fn transform_on_right(&mut self, rhs: &Matrix) {
         let o = &*self * rhs;
@@ -410,6 +458,28 @@ fn dot_product<'a, 'b, T: Iterator<Item=f64>, U: Iterator<Item=f64>>(v: T, u: U)
     sum
 }
 
+fn dot3(v: [f64; 3], u: [f64; 3]) -> f64 {
+    v[0] * u[0] + v[1] * u[1] + v[2] * u[2]
+}
+
+fn sub3(v: [f64; 3], u: [f64; 3]) -> [f64; 3] {
+    [v[0] - u[0], v[1] - u[1], v[2] - u[2]]
+}
+
+fn cross(v: [f64; 3], u: [f64; 3]) -> [f64; 3] {
+    [v[1] * u[2] - v[2] * u[1], v[2] * u[0] - v[0] * u[2], v[0] * u[1] - v[1] * u[0]]
+}
+
+// Scale `v` to a length of 1, or None if it has no length (or isn't a number)
+fn normalize(v: [f64; 3]) -> Option<[f64; 3]> {
+    let norm = dot3(v, v).sqrt();
+    if norm > 0.0 && norm.is_finite() {
+        Some([v[0] / norm, v[1] / norm, v[2] / norm])
+    } else {
+        None
+    }
+}
+
 fn scale_matrix(scalar: f64, mat: &Matrix) -> Matrix {
     let mut result = Matrix::with_capacity(mat.width(), 0.0);
     for row in 0..4 {
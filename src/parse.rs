@@ -1,255 +1,968 @@
-use render::Color;
+use std::fmt;
+use std::collections::{ HashMap, HashSet };
+use std::path::Path;
 
-// TODO: Use Result instead of panics for error handling
-// The error handling here and in mod exec is a mess.
+use render::{ Material, Reflectance };
+use expr::{ self, Expr };
+use source::Sources;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Axis {
     X,
     Y,
     Z
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Variation<'a> {
     pub knob: &'a str,
     pub fst_frame: usize,
     pub last_frame: usize,
     pub min_val: f64,
     pub max_val: f64,
+    pub easing: Easing,
 }
 
-#[derive(Debug)]
+/// A knob's values at several frames, with the values in between interpolated. The frames of
+/// `keys` are in increasing order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keyframes<'a> {
+    pub knob: &'a str,
+    pub keys: Vec<(usize, f64)>,
+    pub interpolation: Interpolation,
+}
+
+/// How the values of a knob between its keyframes are found.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    // Straight lines between keys
+    Linear,
+    // A spline through the keys, whose slope at each key is that of the line between its
+    // neighbors, so that the speed does not jump at keys
+    CatmullRom,
+    // A cubic Bezier curve between each pair of keys, with flat handles, so that the knob eases
+    // in and out of every key without overshooting it
+    Bezier,
+}
+
+const INTERPOLATIONS: [(&str, Interpolation); 3] = [
+    ("linear", Interpolation::Linear),
+    ("catmull-rom", Interpolation::CatmullRom),
+    ("bezier", Interpolation::Bezier),
+];
+
+impl Interpolation {
+    /// The name of the interpolation in scripts.
+    pub fn name(self) -> &'static str {
+        INTERPOLATIONS.iter().find(|&&(_, interpolation)| interpolation == self).unwrap().0
+    }
+}
+
+/// How a knob moves from `min_val` to `max_val` over the frames of a `vary`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Easing {
+    Linear,
+    // Quadratic: starts slowly, ends slowly, or both
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+    // Like `EaseInOut`, but cubic
+    Cubic,
+    // Follows half a period of a cosine wave
+    Sine,
+    // Stays at `min_val` until the last frame
+    Step,
+    // Overshoots `max_val` and bounces back like a dropped ball
+    Bounce,
+    // Overshoots `max_val` and oscillates around it like a spring
+    Elastic,
+}
+
+const EASINGS: [(&str, Easing); 9] = [
+    ("linear", Easing::Linear),
+    ("ease-in", Easing::EaseIn),
+    ("ease-out", Easing::EaseOut),
+    ("ease-in-out", Easing::EaseInOut),
+    ("cubic", Easing::Cubic),
+    ("sine", Easing::Sine),
+    ("step", Easing::Step),
+    ("bounce", Easing::Bounce),
+    ("elastic", Easing::Elastic),
+];
+
+impl Easing {
+    /// The name of the easing in scripts.
+    pub fn name(self) -> &'static str {
+        EASINGS.iter().find(|&&(_, easing)| easing == self).unwrap().0
+    }
+}
+
+/// How a camera projects shapes onto the screen.
+#[derive(Debug, PartialEq)]
+pub enum Projection<'a> {
+    // Things further from the camera are smaller. `fov` is the vertical field of view, in
+    // degrees. Set by `camera`.
+    Perspective { fov: Expr<'a> },
+    // Things are the same size at any distance. The box from `left` to `right`, `bottom` to
+    // `top`, and `near` to `far` in front of the camera is stretched to fill the screen. Set by
+    // `ortho`.
+    Orthographic { left: Expr<'a>, right: Expr<'a>, bottom: Expr<'a>, top: Expr<'a>, near: Expr<'a>, far: Expr<'a> },
+}
+
+// Numeric arguments are expressions, evaluated when the command is run. Other numbers (e.g. of
+// `constants` and `vary`) must be constant.
+#[derive(Debug, PartialEq)]
 pub enum Command<'a> {
+    // A comment (including the leading `#` or `//`), kept so scripts can be written back out
+    Comment(&'a str),
     Push,
     Pop,
     Save(&'a str),
     Display,
-    Move { x: f64, y: f64, z: f64, knob: Option<&'a str> },
-    Rotate(Axis, f64, Option<&'a str>),
-    Scale { x: f64, y: f64, z: f64, knob: Option<&'a str> },
-    Box { x: f64, y: f64, z: f64, w: f64, h: f64, d: f64 }, // TODO: add Option<...>s for cs and constants
-    Sphere { x: f64, y: f64, z: f64, r: f64 },
-    Torus { x: f64, y: f64, z: f64, r0: f64, r1: f64 },
-    Line { x0: f64, y0: f64, z0: f64, x1: f64, y1: f64, z1: f64 },
+    Move { x: Expr<'a>, y: Expr<'a>, z: Expr<'a>, knob: Option<&'a str> },
+    Rotate(Axis, Expr<'a>, Option<&'a str>),
+    Scale { x: Expr<'a>, y: Expr<'a>, z: Expr<'a>, knob: Option<&'a str> },
+    SaveCoordSystem(&'a str),
+    // `constants` is the name of the material defined by a `constants` command, and `cs` is the
+    // name of a coordinate system saved by `save_coord_system` to draw in instead of the top of
+    // the stack. As `constants` comes before the numeric arguments, an expression in the first
+    // argument which is just a knob name must be put in parentheses.
+    Box { x: Expr<'a>, y: Expr<'a>, z: Expr<'a>, w: Expr<'a>, h: Expr<'a>, d: Expr<'a>, constants: Option<&'a str>, cs: Option<&'a str> },
+    Sphere { x: Expr<'a>, y: Expr<'a>, z: Expr<'a>, r: Expr<'a>, constants: Option<&'a str>, cs: Option<&'a str> },
+    Torus { x: Expr<'a>, y: Expr<'a>, z: Expr<'a>, r0: Expr<'a>, r1: Expr<'a>, constants: Option<&'a str>, cs: Option<&'a str> },
+    Line { x0: Expr<'a>, y0: Expr<'a>, z0: Expr<'a>, x1: Expr<'a>, y1: Expr<'a>, z1: Expr<'a>, cs: Option<&'a str> },
+    // For curves, `segments` is the number of edges used to approximate the curve, and the
+    // coordinates are scaled by the value of `knob`. When only one of `knob` and `cs` is given, it
+    // is the coordinate system if one of that name was saved earlier in the script, and the knob
+    // otherwise.
+    Circle { x: Expr<'a>, y: Expr<'a>, z: Expr<'a>, r: Expr<'a>, segments: Option<usize>, knob: Option<&'a str>, cs: Option<&'a str> },
+    Bezier { p0: [Expr<'a>; 3], p1: [Expr<'a>; 3], p2: [Expr<'a>; 3], p3: [Expr<'a>; 3], segments: Option<usize>, knob: Option<&'a str>, cs: Option<&'a str> },
+    Hermite { p0: [Expr<'a>; 3], p1: [Expr<'a>; 3], m0: [Expr<'a>; 3], m1: [Expr<'a>; 3], segments: Option<usize>, knob: Option<&'a str>, cs: Option<&'a str> },
     Frames(usize),
+    // The width and height of the image (or of each frame) in pixels
+    Resolution(usize, usize),
     Basename(&'a str),
+    // Blur each frame of an animation by averaging `samples` sub-frames, spread over the fraction
+    // `shutter` of the time between frames (centered on the frame)
+    MotionBlur { samples: usize, shutter: f64 },
     Vary(Variation<'a>),
-    Set(&'a str, f64),
-    SetKnobs(f64),
-    Ambient(f64, f64, f64), // r, g, b
-    Light(f64, f64, f64, f64, f64, f64), // r, g, b, x, y, z
-    //Constants {
-    //    name: &'a str,
-    //    ka_r: f64,
-    //    kd_r: f64,
-    //    ks_r: f64,
-    //    ka_g: f64,
-    //    kd_g: f64,
-    //    ks_g: f64,
-    //    ka_b: f64,
-    //    kd_b: f64,
-    //    ks_b: f64,
-    //    // TODO: what's up with R, G, B "intensities" (optional args described in MDL.spec)
-    //},
-}
-
-pub fn parse<'a>(script: &'a str) -> Result<Vec<Command<'a>>, &'static str> {
-    let mut cmds = vec![];
-
-    for mut line in script.lines() {
-        skip_linespace(&mut line);
-        // Skip blank lines and comments
-        // TODO: handle comments at end of lines with commands
-        // TODO: make this not a jank one-liner
-        if line.chars().nth(0) == Some('#') ||
-                line.len() == 0 ||
-                (line.chars().nth(0) == Some('/') &&
-                 line.chars().nth(1) == Some('/')) {
-            continue;
-        }
-
-        let command = match next_lexeme(&mut line)? {
-            "push" => Command::Push,
-
-            "pop" => Command::Pop,
-
-            "save" => {
-                let filename = next_lexeme(&mut line)?;
-                Command::Save(filename)
-            },
+    Keyframes(Keyframes<'a>),
+    Set(&'a str, Expr<'a>),
+    SetKnobs(Expr<'a>),
+    // Save the values of every knob (as set by `set` and `setknobs`) as a knob list
+    SaveKnobs(&'a str),
+    // Blend every knob from the values in one knob list to those in another over a range of frames
+    Tween { fst_frame: usize, last_frame: usize, from: &'a str, to: &'a str },
+    // Lights affect the shapes drawn after them, until the `pop` matching the last `push` before them
+    Ambient(Expr<'a>, Expr<'a>, Expr<'a>), // r, g, b
+    Light(Expr<'a>, Expr<'a>, Expr<'a>, Expr<'a>, Expr<'a>, Expr<'a>), // r, g, b, x, y, z
+    // Look from `eye` towards `aim`, with `up` pointing up on the screen. The camera affects the
+    // shapes drawn after it.
+    Camera { eye: [Expr<'a>; 3], aim: [Expr<'a>; 3], up: [Expr<'a>; 3], projection: Projection<'a> },
+    Constants(&'a str, Material),
+    // A block of commands that can be run with `call`, with the names of its parameters
+    Def { name: &'a str, params: Vec<&'a str>, body: Vec<Command<'a>> },
+    Call(&'a str, Vec<Expr<'a>>),
+    // Run `body` `count` times, with the variable `var` set to 0, 1, ..., count - 1
+    Repeat { count: Expr<'a>, var: &'a str, body: Vec<Command<'a>> },
+    // The commands of another script file, with the path as written in the `include` command
+    Include { path: &'a str, body: Vec<Command<'a>> },
+}
 
-            "display" => Command::Display,
+/// Where a command was written: the index of the file in the script's `Sources` (0 for scripts
+/// parsed without sources) and the (1-based) line number. For commands with bodies (`def`,
+/// `repeat`, and `include`), `body` has the locations of the commands in the body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: usize,
+    pub line: usize,
+    pub body: Vec<Location>,
+}
 
-            "move" => {
-                Command::Move {
-                    x: next_float(&mut line),
-                    y: next_float(&mut line),
-                    z: next_float(&mut line),
-                    knob: next_lexeme(&mut line).ok()
-                }
-            },
+/// An error found while parsing a script. `line` and `column` are 1-based, and `lexeme` is the
+/// offending piece of text (or `None` if the line ended before `expected` was found). `file` is
+/// the path of the file containing the error, if it was read from a file. If that file was
+/// included by another, `included_from` has the path and line of each `include` command that led
+/// to it, innermost first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub lexeme: Option<String>,
+    pub expected: String,
+    pub included_from: Vec<(String, usize)>,
+}
 
-            "rotate" => {
-                Command::Rotate(
-                    next_axis(&mut line),
-                    next_float(&mut line),
-                    next_lexeme(&mut line).ok())
-            },
+impl ParseError {
+    pub fn in_file(mut self, path: &Path) -> ParseError {
+        self.file = Some(path.display().to_string());
+        self
+    }
 
-            "scale" => {
-                Command::Scale {
-                    x: next_float(&mut line),
-                    y: next_float(&mut line),
-                    z: next_float(&mut line),
-                    knob: next_lexeme(&mut line).ok()
-                }
-            },
+    /// Set the `include` commands that led to the file containing the error, innermost first.
+    pub fn included_from(mut self, includes: Vec<(String, usize)>) -> ParseError {
+        self.included_from = includes;
+        self
+    }
+}
 
-            "box" => {
-                Command::Box {
-                    x: next_float(&mut line),
-                    y: next_float(&mut line),
-                    z: next_float(&mut line),
-                    w: next_float(&mut line),
-                    h: next_float(&mut line),
-                    d: next_float(&mut line)
-                }
-            },
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}, ", file)?;
+        }
+        write!(f, "line {}, column {}: expected {}, found ", self.line, self.column, self.expected)?;
+        match self.lexeme {
+            Some(ref lexeme) => write!(f, "'{}'", lexeme)?,
+            None => write!(f, "end of line")?,
+        }
+        for &(ref file, line) in &self.included_from {
+            write!(f, "\n    included from {}, line {}", file, line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse `script` into a list of commands. If any lines are malformed, every error in the
+/// script is returned (not just the first one). `include` commands are not allowed, as there is
+/// no file to include relative to; use `parse_sources` for scripts read from files.
+pub fn parse<'a>(script: &'a str) -> Result<Vec<Command<'a>>, Vec<ParseError>> {
+    let mut parser = Parser { sources: None, macros: HashMap::new(), coord_systems: HashSet::new(), errors: vec![] };
+    let block = parser.parse_file(script, 0);
+    parser.finish(block).map(|(cmds, _)| cmds)
+}
+
+/// Parse the root script of `sources`, including the commands of the files it includes. Along
+/// with the commands, the location of each is returned (in the same order).
+pub fn parse_sources<'a>(sources: &'a Sources) -> Result<(Vec<Command<'a>>, Vec<Location>), Vec<ParseError>> {
+    let mut parser = Parser { sources: Some(sources), macros: HashMap::new(), coord_systems: HashSet::new(), errors: vec![] };
+    let block = parser.parse_file(sources.root(), 0);
+    parser.finish(block)
+}
+
+// State shared between all of the files parsed for a script
+struct Parser<'a> {
+    sources: Option<&'a Sources>,
+    // The number of parameters of each block defined with `def`
+    macros: HashMap<&'a str, usize>,
+    // The coordinate systems saved with `save_coord_system` so far
+    coord_systems: HashSet<&'a str>,
+    errors: Vec<ParseError>,
+}
+
+impl<'a> Parser<'a> {
+    fn finish(self, block: Block<'a>) -> Result<(Vec<Command<'a>>, Vec<Location>), Vec<ParseError>> {
+        if self.errors.is_empty() {
+            Ok((block.cmds, block.locations))
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    // Parse `script`, the `file`th file of `self.sources`, adding any errors to `self.errors`
+    fn parse_file(&mut self, script: &'a str, file: usize) -> Block<'a> {
+        let mut top = Block::new();
+        let mut errors = vec![];
+        // Blocks (e.g. `def` or `repeat`) whose `end` has not been reached yet, innermost last
+        let mut blocks: Vec<OpenBlock<'a>> = vec![];
+
+        for (i, text) in script.lines().enumerate() {
+            let mut line = Line::new(i + 1, text, variables_in_scope(&blocks));
+            let location = Location { file, line: i + 1, body: vec![] };
+            // Skip blank lines, and keep comments on lines of their own
+            if let Some(comment) = line.comment() {
+                push_command(&mut top, &mut blocks, Command::Comment(comment), location);
+                continue;
+            }
+            if line.peek_lexeme().is_none() {
+                continue;
+            }
+
+            let result = match line.peek_lexeme() {
+                Some("def") => {
+                    let macros = &mut self.macros;
+                    parse_def(&mut line, &blocks).map(|block| {
+                        match block.kind {
+                            BlockKind::Def { name, ref params } => {
+                                macros.insert(name, params.len());
+                            },
+                            BlockKind::Repeat { .. } => {},
+                        }
+                        blocks.push(block);
+                    })
+                },
+                Some("repeat") => {
+                    parse_repeat(&mut line).map(|block| blocks.push(block))
+                },
+                Some("end") => {
+                    let end = line.opt_lexeme();
+                    match blocks.pop() {
+                        Some(block) => {
+                            let (command, location) = block.close(file);
+                            push_command(&mut top, &mut blocks, command, location);
+                            Ok(())
+                        },
+                        None => Err(line.error_at(end, "a command")),
+                    }
+                },
+                Some("include") => {
+                    self.parse_include(&mut line, file, &blocks).map(|(command, body)| {
+                        top.push(command, Location { body, ..location.clone() })
+                    })
+                },
+                _ => {
+                    let coord_systems = &mut self.coord_systems;
+                    parse_command(&mut line, &self.macros, coord_systems).map(|command| {
+                        if let Command::SaveCoordSystem(name) = command {
+                            coord_systems.insert(name);
+                        }
+                        push_command(&mut top, &mut blocks, command, location.clone())
+                    })
+                },
+            };
+            // Anything other than a comment left after the command is an error
+            let result = result.and_then(|()| match line.peek_lexeme() {
+                Some(extra) => Err(line.error_at(Some(extra), "end of line")),
+                None => Ok(()),
+            });
+            match result {
+                // A comment at the end of a line is kept as if it were on the next line
+                Ok(()) => if let Some(comment) = line.comment() {
+                    push_command(&mut top, &mut blocks, Command::Comment(comment), location);
+                },
+                Err(e) => errors.push(e),
+            }
+        }
+
+        // Every block left open is missing its `end`
+        for block in blocks {
+            errors.push(block.start);
+        }
+
+        if let Some(sources) = self.sources {
+            for e in &mut errors {
+                *e = e.clone().in_file(sources.path(file)).included_from(sources.include_chain(file));
+            }
+        }
+        self.errors.extend(errors);
+        top
+    }
+
+    // Parse an `include` command and the file it includes, giving the locations of the commands
+    // in the file along with the command
+    fn parse_include(&mut self, line: &mut Line<'a>, file: usize, blocks: &[OpenBlock<'a>]) -> Result<(Command<'a>, Vec<Location>), ParseError> {
+        let keyword = line.next_lexeme("include")?;
+        if !blocks.is_empty() {
+            return Err(line.error_at(Some(keyword), "a command ('include' must be at the top level)"));
+        }
+        let path = line.next_lexeme("a file path")?;
+        // The file was loaded (or failed to load) when `self.sources` was made
+        let included = match self.sources.and_then(|sources| sources.included(file, line.num)) {
+            Some(included) => included,
+            None => return Err(line.error_at(Some(path), "a file that can be included")),
+        };
+        let sources = self.sources.unwrap();
+        let body = self.parse_file(sources.text(included), included);
+        Ok((Command::Include { path, body: body.cmds }, body.locations))
+    }
+}
+
+// Add `command` to the innermost open block, or to `top` if no blocks are open
+fn push_command<'a>(top: &mut Block<'a>, blocks: &mut Vec<OpenBlock<'a>>, command: Command<'a>, location: Location) {
+    match blocks.last_mut() {
+        Some(block) => block.body.push(command, location),
+        None => top.push(command, location),
+    }
+}
+
+/// A list of commands and their locations.
+struct Block<'a> {
+    cmds: Vec<Command<'a>>,
+    locations: Vec<Location>,
+}
+
+impl<'a> Block<'a> {
+    fn new() -> Block<'a> {
+        Block { cmds: vec![], locations: vec![] }
+    }
+
+    fn push(&mut self, command: Command<'a>, location: Location) {
+        self.cmds.push(command);
+        self.locations.push(location);
+    }
+}
+
+/// A block (e.g. `def` or `repeat`) whose commands are still being parsed.
+struct OpenBlock<'a> {
+    kind: BlockKind<'a>,
+    body: Block<'a>,
+    /// The error reported if the block is never closed, pointing at its first line
+    start: ParseError,
+}
 
-            "sphere" => {
-                Command::Sphere {
-                    x: next_float(&mut line),
-                    y: next_float(&mut line),
-                    z: next_float(&mut line),
-                    r: next_float(&mut line),
+enum BlockKind<'a> {
+    Def { name: &'a str, params: Vec<&'a str> },
+    Repeat { count: Expr<'a>, var: &'a str },
+}
+
+impl<'a> OpenBlock<'a> {
+    // Make the command for the block, which is in the `file`th file
+    fn close(self, file: usize) -> (Command<'a>, Location) {
+        let body = self.body.cmds;
+        let command = match self.kind {
+            BlockKind::Def { name, params } => Command::Def { name, params, body },
+            BlockKind::Repeat { count, var } => Command::Repeat { count, var, body },
+        };
+        (command, Location { file, line: self.start.line, body: self.body.locations })
+    }
+}
+
+// The variables which can be used as numeric arguments inside the innermost of `blocks`
+fn variables_in_scope<'a>(blocks: &Vec<OpenBlock<'a>>) -> Vec<&'a str> {
+    let mut vars = vec![];
+    for block in blocks {
+        match block.kind {
+            BlockKind::Def { ref params, .. } => vars.extend(params),
+            BlockKind::Repeat { var, .. } => vars.push(var),
+        }
+    }
+    vars
+}
+
+fn parse_def<'a>(line: &mut Line<'a>, blocks: &[OpenBlock<'a>]) -> Result<OpenBlock<'a>, ParseError> {
+    let keyword = line.next_lexeme("def")?;
+    let start = line.error_at(Some(keyword), "a matching 'end'");
+    if !blocks.is_empty() {
+        return Err(line.error_at(Some(keyword), "a command ('def' blocks must be at the top level)"));
+    }
+    let name = line.next_name("a block name")?;
+    let mut params = vec![];
+    while line.peek_lexeme().is_some() {
+        params.push(line.next_name("a parameter name")?);
+    }
+    Ok(OpenBlock {
+        kind: BlockKind::Def { name, params },
+        body: Block::new(),
+        start,
+    })
+}
+
+fn parse_repeat<'a>(line: &mut Line<'a>) -> Result<OpenBlock<'a>, ParseError> {
+    let keyword = line.next_lexeme("repeat")?;
+    let start = line.error_at(Some(keyword), "a matching 'end'");
+    let count = line.next_count()?;
+    let var = line.next_name("a loop variable name")?;
+    Ok(OpenBlock {
+        kind: BlockKind::Repeat { count, var },
+        body: Block::new(),
+        start,
+    })
+}
+
+fn parse_command<'a>(line: &mut Line<'a>, macros: &HashMap<&'a str, usize>, coord_systems: &HashSet<&'a str>)
+                     -> Result<Command<'a>, ParseError> {
+    let command = match line.next_lexeme("a command")? {
+        "push" => Command::Push,
+
+        "pop" => Command::Pop,
+
+        "save" => Command::Save(line.next_lexeme("a filename")?),
+
+        "display" => Command::Display,
+
+        "save_coord_system" => Command::SaveCoordSystem(line.next_lexeme("a coordinate system name")?),
+
+        "move" => {
+            Command::Move {
+                x: line.next_value()?,
+                y: line.next_value()?,
+                z: line.next_value()?,
+                knob: line.opt_trailing_name()
+            }
+        },
+
+        "rotate" => {
+            Command::Rotate(
+                line.next_axis()?,
+                line.next_value()?,
+                line.opt_trailing_name())
+        },
+
+        "scale" => {
+            Command::Scale {
+                x: line.next_value()?,
+                y: line.next_value()?,
+                z: line.next_value()?,
+                knob: line.opt_trailing_name()
+            }
+        },
+
+        "box" => {
+            let constants = line.opt_name();
+            Command::Box {
+                x: line.next_value()?,
+                y: line.next_value()?,
+                z: line.next_value()?,
+                w: line.next_value()?,
+                h: line.next_value()?,
+                d: line.next_value()?,
+                constants,
+                cs: line.opt_trailing_name()
+            }
+        },
+
+        "sphere" => {
+            let constants = line.opt_name();
+            Command::Sphere {
+                x: line.next_value()?,
+                y: line.next_value()?,
+                z: line.next_value()?,
+                r: line.next_value()?,
+                constants,
+                cs: line.opt_trailing_name()
+            }
+        },
+
+        "torus" => {
+            let constants = line.opt_name();
+            Command::Torus {
+                x: line.next_value()?,
+                y: line.next_value()?,
+                z: line.next_value()?,
+                r0: line.next_value()?,
+                r1: line.next_value()?,
+                constants,
+                cs: line.opt_trailing_name()
+            }
+        },
+
+        "line" => {
+            Command::Line {
+                x0: line.next_value()?,
+                y0: line.next_value()?,
+                z0: line.next_value()?,
+                x1: line.next_value()?,
+                y1: line.next_value()?,
+                z1: line.next_value()?,
+                cs: line.opt_trailing_name()
+            }
+        },
+
+        "circle" => {
+            let (x, y, z, r) = (line.next_value()?, line.next_value()?, line.next_value()?, line.next_value()?);
+            let segments = line.opt_usize();
+            let (knob, cs) = line.opt_knob_and_cs(coord_systems);
+            Command::Circle { x, y, z, r, segments, knob, cs }
+        },
+
+        "bezier" => {
+            let (p0, p1, p2, p3) = (line.next_point()?, line.next_point()?, line.next_point()?, line.next_point()?);
+            let segments = line.opt_usize();
+            let (knob, cs) = line.opt_knob_and_cs(coord_systems);
+            Command::Bezier { p0, p1, p2, p3, segments, knob, cs }
+        },
+
+        "hermite" => {
+            let (p0, p1, m0, m1) = (line.next_point()?, line.next_point()?, line.next_point()?, line.next_point()?);
+            let segments = line.opt_usize();
+            let (knob, cs) = line.opt_knob_and_cs(coord_systems);
+            Command::Hermite { p0, p1, m0, m1, segments, knob, cs }
+        },
+
+        "frames" => Command::Frames(line.next_dimension()?),
+
+        "resolution" => Command::Resolution(line.next_dimension()?, line.next_dimension()?),
+
+        "basename" => Command::Basename(line.next_lexeme("a basename")?),
+
+        "motionblur" => Command::MotionBlur { samples: line.next_dimension()?, shutter: line.next_float()? },
+
+        "vary" => {
+            Command::Vary(Variation {
+                knob: line.next_lexeme("a knob name")?,
+                fst_frame: line.next_usize()?,
+                last_frame: line.next_usize()?,
+                min_val: line.next_float()?,
+                max_val: line.next_float()?,
+                easing: line.opt_easing()?
+            })
+        },
+
+        "keyframes" => {
+            let knob = line.next_lexeme("a knob name")?;
+            let mut keys: Vec<(usize, f64)> = vec![line.next_key()?];
+            let mut interpolation = Interpolation::Linear;
+            while let Some(lexeme) = line.peek_lexeme() {
+                if !lexeme.contains(':') {
+                    // The interpolation, which must come last
+                    interpolation = line.next_interpolation()?;
+                    break;
                 }
-            },
+                let (frame, val) = line.next_key()?;
+                if frame <= keys[keys.len() - 1].0 {
+                    return Err(line.error_at(Some(lexeme), "a keyframe after the previous one"));
+                }
+                keys.push((frame, val));
+            }
+            Command::Keyframes(Keyframes { knob, keys, interpolation })
+        },
+
+        "set" => Command::Set(line.next_lexeme("a knob name")?, line.next_value()?),
+
+        "setknobs" => Command::SetKnobs(line.next_value()?),
+
+        "save_knobs" => Command::SaveKnobs(line.next_lexeme("a knob list name")?),
+
+        "tween" => {
+            Command::Tween {
+                fst_frame: line.next_usize()?,
+                last_frame: line.next_usize()?,
+                from: line.next_lexeme("a knob list name")?,
+                to: line.next_lexeme("a knob list name")?
+            }
+        },
 
-            "torus" => {
-                Command::Torus {
-                    x: next_float(&mut line),
-                    y: next_float(&mut line),
-                    z: next_float(&mut line),
-                    r0: next_float(&mut line),
-                    r1: next_float(&mut line),
+        "ambient" => {
+            Command::Ambient(line.next_value()?, line.next_value()?, line.next_value()?)
+        },
+
+        "light" => {
+            Command::Light(
+                line.next_value()?,
+                line.next_value()?,
+                line.next_value()?,
+                line.next_value()?,
+                line.next_value()?,
+                line.next_value()?)
+        },
+
+        "camera" => {
+            Command::Camera {
+                eye: line.next_point()?,
+                aim: line.next_point()?,
+                up: line.next_point()?,
+                projection: Projection::Perspective { fov: line.next_value()? }
+            }
+        },
+
+        "ortho" => {
+            Command::Camera {
+                eye: line.next_point()?,
+                aim: line.next_point()?,
+                up: line.next_point()?,
+                projection: Projection::Orthographic {
+                    left: line.next_value()?,
+                    right: line.next_value()?,
+                    bottom: line.next_value()?,
+                    top: line.next_value()?,
+                    near: line.next_value()?,
+                    far: line.next_value()?
                 }
-            },
+            }
+        },
+
+        "constants" => {
+            let name = line.next_lexeme("a constants name")?;
+            let r = line.next_reflectance()?;
+            let g = line.next_reflectance()?;
+            let b = line.next_reflectance()?;
+            // The intensities are optional, but if one is given all three must be
+            let intensity = match line.opt_float()? {
+                Some(r) => Some((r, line.next_float()?, line.next_float()?)),
+                None => None,
+            };
+            Command::Constants(name, Material { r, g, b, intensity })
+        },
 
-            "line" => {
-                Command::Line {
-                    x0: next_float(&mut line),
-                    y0: next_float(&mut line),
-                    z0: next_float(&mut line),
-                    x1: next_float(&mut line),
-                    y1: next_float(&mut line),
-                    z1: next_float(&mut line)
+        "call" => {
+            let name = line.next_lexeme("a block name")?;
+            let arity = match macros.get(name) {
+                Some(&arity) => arity,
+                None => return Err(line.error_at(Some(name), "the name of a block defined with 'def'")),
+            };
+            let mut args = vec![];
+            for _ in 0..arity {
+                if line.peek_lexeme().is_none() {
+                    return Err(line.error_at(None, &format!("{} arguments for '{}'", arity, name)));
                 }
-            },
+                args.push(line.next_value()?);
+            }
+            if let Some(extra) = line.peek_lexeme() {
+                return Err(line.error_at(Some(extra), &format!("only {} arguments for '{}'", arity, name)));
+            }
+            Command::Call(name, args)
+        },
 
-            "frames" => Command::Frames(next_usize(&mut line)),
+        other => {
+            return Err(line.error_at(Some(other), "a command"));
+        }
+    };
+    Ok(command)
+}
 
-            "basename" => Command::Basename(next_lexeme(&mut line)?),
+fn is_comment(text: &str) -> bool {
+    text.starts_with('#') || text.starts_with("//")
+}
 
-            "vary" => {
-                Command::Vary(Variation {
-                    knob: next_lexeme(&mut line)?,
-                    fst_frame: next_usize(&mut line),
-                    last_frame: next_usize(&mut line),
-                    min_val: next_float(&mut line),
-                    max_val: next_float(&mut line)
-                })
+/// A single line of a script, consumed lexeme by lexeme.
+struct Line<'a> {
+    /// 1-based line number
+    num: usize,
+    /// The full text of the line
+    text: &'a str,
+    /// The part of the line not yet consumed
+    rest: &'a str,
+    /// Names which can be used in place of numbers
+    vars: Vec<&'a str>,
+}
+
+impl<'a> Line<'a> {
+    fn new(num: usize, text: &'a str, vars: Vec<&'a str>) -> Line<'a> {
+        Line { num, text, rest: text, vars }
+    }
+
+    /// Make an error for `lexeme` (which must be a slice of `self.text`), or for the end of
+    /// the line if `lexeme` is `None`.
+    fn error_at(&self, lexeme: Option<&'a str>, expected: &str) -> ParseError {
+        let offset = match lexeme {
+            Some(lexeme) => lexeme.as_ptr() as usize - self.text.as_ptr() as usize,
+            None => self.text.trim_end().len(),
+        };
+        ParseError {
+            file: None,
+            line: self.num,
+            column: self.text[..offset].chars().count() + 1,
+            lexeme: lexeme.map(String::from),
+            expected: expected.to_string(),
+            included_from: vec![],
+        }
+    }
+
+    /// Get the comment that the rest of the line consists of, if it is one.
+    fn comment(&self) -> Option<&'a str> {
+        let rest = self.rest.trim();
+        if is_comment(rest) {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+
+    /// Get the next lexeme without consuming it, if there is one.
+    /// Whitespace inside parentheses does not end a lexeme, so that expressions like `(x + 1)`
+    /// are one lexeme. A comment ends the line.
+    fn peek_lexeme(&self) -> Option<&'a str> {
+        let rest = self.rest.trim_start_matches([' ', '\t']);
+        if rest.is_empty() || is_comment(rest) {
+            return None;
+        }
+        let mut depth = 0;
+        for (i, c) in rest.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' if depth > 0 => depth -= 1,
+                c if c.is_whitespace() && depth == 0 => return Some(&rest[..i]),
+                _ => {},
+            }
+        }
+        Some(rest)
+    }
+
+    fn next_lexeme(&mut self, expected: &str) -> Result<&'a str, ParseError> {
+        match self.peek_lexeme() {
+            Some(lexeme) => {
+                // Continue after the lexeme, which is a slice of `self.rest`
+                let end = lexeme.as_ptr() as usize - self.rest.as_ptr() as usize + lexeme.len();
+                self.rest = &self.rest[end..];
+                Ok(lexeme)
             },
+            None => Err(self.error_at(None, expected)),
+        }
+    }
 
-            "set" => Command::Set(next_lexeme(&mut line)?, next_float(&mut line)),
+    /// Get the next lexeme, which must be a name rather than a number.
+    fn next_name(&mut self, expected: &str) -> Result<&'a str, ParseError> {
+        let lexeme = self.next_lexeme(expected)?;
+        if lexeme.parse::<f64>().is_ok() {
+            Err(self.error_at(Some(lexeme), expected))
+        } else {
+            Ok(lexeme)
+        }
+    }
+
+    /// Get the next lexeme if there is one, for optional arguments.
+    fn opt_lexeme(&mut self) -> Option<&'a str> {
+        self.next_lexeme("").ok()
+    }
 
-            "setknobs" => Command::SetKnobs(next_float(&mut line)),
+    /// Get the next lexeme if it is a name rather than a number, for optional names at the end of
+    /// a command (e.g. the knob of `move`), so that an extra number is reported as extra input.
+    fn opt_trailing_name(&mut self) -> Option<&'a str> {
+        match self.peek_lexeme() {
+            Some(lexeme) if lexeme.parse::<f64>().is_err() => self.opt_lexeme(),
+            _ => None,
+        }
+    }
 
-            "ambient" => {
-                Command::Ambient(next_float(&mut line), next_float(&mut line), next_float(&mut line))
+    /// Get the next lexeme if it is a name rather than an expression, for optional names that
+    /// come before numeric arguments (e.g. the constants of `box`). Names of variables and the
+    /// built-in names `pi` and `frame` are taken to be expressions, as is anything not starting
+    /// with a letter or underscore.
+    fn opt_name(&mut self) -> Option<&'a str> {
+        match self.peek_lexeme() {
+            Some(lexeme) if lexeme.starts_with(|c: char| c.is_alphabetic() || c == '_') &&
+                    !lexeme.contains('(') &&
+                    !self.vars.contains(&lexeme) &&
+                    lexeme != "pi" && lexeme != "frame" => {
+                self.opt_lexeme()
             },
+            _ => None,
+        }
+    }
+
+    /// Get the optional knob and coordinate system at the end of a curve command. A name on its
+    /// own is the coordinate system if `coord_systems` (those saved so far) has one by that name,
+    /// and the knob otherwise, like the optional constants of `box` are told apart from its
+    /// numbers.
+    fn opt_knob_and_cs(&mut self, coord_systems: &HashSet<&'a str>) -> (Option<&'a str>, Option<&'a str>) {
+        match (self.opt_trailing_name(), self.opt_trailing_name()) {
+            (Some(name), None) if coord_systems.contains(name) => (None, Some(name)),
+            (knob, cs) => (knob, cs),
+        }
+    }
 
-            "light" => {
-                Command::Light(
-                    next_float(&mut line),
-                    next_float(&mut line),
-                    next_float(&mut line),
-                    next_float(&mut line),
-                    next_float(&mut line),
-                    next_float(&mut line))
+    /// Get the next lexeme if it is a non-negative integer, for optional counts that come before
+    /// other optional arguments (e.g. the segments of `circle`, which may be followed by a
+    /// coordinate system).
+    fn opt_usize(&mut self) -> Option<usize> {
+        match self.peek_lexeme().map(|lexeme| lexeme.parse::<usize>()) {
+            Some(Ok(n)) => {
+                self.opt_lexeme();
+                Some(n)
             },
+            _ => None,
+        }
+    }
 
-            other => {
-                panic!("Error! Unknown command '{}'!", other);
-            }
-        };
-        cmds.push(command);
-        // TODO: error on extra input
+    fn next_usize(&mut self) -> Result<usize, ParseError> {
+        let expected = "a non-negative integer";
+        let lexeme = self.next_lexeme(expected)?;
+        lexeme.parse::<usize>().map_err(|_| self.error_at(Some(lexeme), expected))
     }
-    Ok(cmds)
-}
 
-fn skip_linespace<'a, 'b>(src: &'b mut &'a str) {
-    for (i, c) in src.char_indices() {
-        // Plow through src until we hit a newline or non-linespace char
-        if c == '\n' || (c != ' ' && c != '\t') {
-            // Assign src to slice after the whitespace
-            *src = src.split_at(i).1;
-            return;
+    fn next_dimension(&mut self) -> Result<usize, ParseError> {
+        let expected = "a positive integer";
+        let lexeme = self.next_lexeme(expected)?;
+        match lexeme.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(self.error_at(Some(lexeme), expected)),
         }
     }
-    *src = ""; // no input after whitespace
-}
 
-fn next_lexeme<'a, 'b>(src: &'b mut &'a str) -> Result<&'a str, &'static str> {
-    skip_linespace(src);
-    if src.len() == 0 {
-        // TODO: take metadata as a parameter to have better error messages
-        Err("Unexpected end of line in script!")
-    } else {
-        for (i, c) in src.char_indices() {
-            if c.is_whitespace() {
-                let (lexeme, rest) = src.split_at(i);
-                *src = rest;
-                return Ok(lexeme);
-            }
+    /// Get the next expression, which must be constant and finite (so that it can be written back
+    /// out as a number).
+    fn next_float(&mut self) -> Result<f64, ParseError> {
+        let expected = "a constant number";
+        let lexeme = self.next_lexeme(expected)?;
+        match self.parse_expr(lexeme)?.constant_value() {
+            Some(x) if x.is_finite() => Ok(x),
+            Some(_) => Err(self.error_at(Some(lexeme), "a finite number")),
+            None => Err(self.error_at(Some(lexeme), expected)),
         }
-        // If the loop finished, all of src is one lexeme
-        let lexeme = *src;
-        *src = ""; // No input after lexeme
-        Ok(lexeme)
     }
-}
 
-fn next_usize(srcref: &mut &str) -> usize {
-    if let Ok(lexeme) = next_lexeme(srcref) {
-        match lexeme.parse::<usize>() {
-            Ok(x) => x,
-            Err(_) => panic!("Error! Expected floating point number, found {}", lexeme)
+    /// Get the next expression.
+    fn next_value(&mut self) -> Result<Expr<'a>, ParseError> {
+        let lexeme = self.next_lexeme("a number")?;
+        self.parse_expr(lexeme)
+    }
+
+    /// Get the next expression, which must be a non-negative integer if it is constant.
+    fn next_count(&mut self) -> Result<Expr<'a>, ParseError> {
+        let expected = "a non-negative integer";
+        let lexeme = self.next_lexeme(expected)?;
+        let count = self.parse_expr(lexeme)?;
+        match count.constant_value() {
+            Some(n) if n < 0.0 || n.fract() != 0.0 => Err(self.error_at(Some(lexeme), expected)),
+            _ => Ok(count),
         }
-    } else {
-        panic!("Error! Expected floating point number, found end of line");
     }
-}
 
-fn next_float(srcref: &mut &str) -> f64 {
-    if let Ok(lexeme) = next_lexeme(srcref) {
-        match lexeme.parse::<f64>() {
-            Ok(x) => x,
-            Err(_) => panic!("Error! Expected floating point number, found {}", lexeme)
+    fn parse_expr(&self, lexeme: &'a str) -> Result<Expr<'a>, ParseError> {
+        expr::parse(lexeme, &self.vars).map_err(|e| {
+            // If the expression ended too early, point at the whole thing
+            let at = if e.at.is_empty() { lexeme } else { e.at };
+            self.error_at(Some(at), e.expected)
+        })
+    }
+
+    /// Get the next three expressions as the x, y, and z coordinates of a point.
+    fn next_point(&mut self) -> Result<[Expr<'a>; 3], ParseError> {
+        Ok([self.next_value()?, self.next_value()?, self.next_value()?])
+    }
+
+    /// Get the next number if the line has not ended, for optional numeric arguments.
+    fn opt_float(&mut self) -> Result<Option<f64>, ParseError> {
+        match self.peek_lexeme() {
+            Some(_) => self.next_float().map(Some),
+            None => Ok(None),
         }
-    } else {
-        panic!("Error! Expected floating point number, found end of line");
     }
-}
 
-fn next_axis(srcref: &mut &str) -> Axis {
-    let lexeme = next_lexeme(srcref);
-    match lexeme {
-        Ok("x") => Axis::X,
-        Ok("y") => Axis::Y,
-        Ok("z") => Axis::Z,
-        Ok(word) => panic!("Error! Expected floating point number, found {}", word),
-        Err(_) => panic!("Error! Expected floating points number, found end of line")
+    /// Get the ambient, diffuse, and specular constants for one color channel.
+    fn next_reflectance(&mut self) -> Result<Reflectance, ParseError> {
+        Ok(Reflectance {
+            ambient: self.next_float()?,
+            diffuse: self.next_float()?,
+            specular: self.next_float()?,
+        })
+    }
+
+    /// Get the easing of a `vary`, which is linear if none is given.
+    fn opt_easing(&mut self) -> Result<Easing, ParseError> {
+        let expected = "an easing (linear, ease-in, ease-out, ease-in-out, cubic, sine, step, bounce, or elastic)";
+        match self.opt_lexeme() {
+            Some(lexeme) => {
+                match EASINGS.iter().find(|&&(name, _)| name == lexeme) {
+                    Some(&(_, easing)) => Ok(easing),
+                    None => Err(self.error_at(Some(lexeme), expected)),
+                }
+            },
+            None => Ok(Easing::Linear),
+        }
+    }
+
+    /// Get the next keyframe, written as `frame:value`.
+    fn next_key(&mut self) -> Result<(usize, f64), ParseError> {
+        let expected = "a keyframe (frame:value)";
+        let lexeme = self.next_lexeme(expected)?;
+        let mut parts = lexeme.splitn(2, ':');
+        let frame = parts.next().and_then(|frame| frame.parse::<usize>().ok());
+        let val = parts.next().and_then(|val| expr::parse(val, &[]).ok()).and_then(|val| val.constant_value())
+            .filter(|val| val.is_finite());
+        match (frame, val) {
+            (Some(frame), Some(val)) => Ok((frame, val)),
+            _ => Err(self.error_at(Some(lexeme), expected)),
+        }
+    }
+
+    fn next_interpolation(&mut self) -> Result<Interpolation, ParseError> {
+        let expected = "a keyframe (frame:value) or an interpolation (linear, catmull-rom, or bezier)";
+        let lexeme = self.next_lexeme(expected)?;
+        match INTERPOLATIONS.iter().find(|&&(name, _)| name == lexeme) {
+            Some(&(_, interpolation)) => Ok(interpolation),
+            None => Err(self.error_at(Some(lexeme), expected)),
+        }
+    }
+
+    fn next_axis(&mut self) -> Result<Axis, ParseError> {
+        let expected = "an axis (x, y, or z)";
+        match self.next_lexeme(expected)? {
+            "x" => Ok(Axis::X),
+            "y" => Ok(Axis::Y),
+            "z" => Ok(Axis::Z),
+            other => Err(self.error_at(Some(other), expected)),
+        }
     }
 }
@@ -1,8 +1,7 @@
-use std::error::Error;
 use std::path::Path;
 use std::fs::File;
+use std::io::{ self, BufWriter };
 use std::io::prelude::*;
-use std::io::BufWriter;
 use std::process::Command;
 
 use std::sync::mpsc::Receiver;
@@ -12,39 +11,36 @@ use worker::WorkerPool;
 use render::Screen;
 use consts::*;
 
-pub fn save_ppm(image: &Screen, filename: &str) {
-    let path = Path::new(filename);
-    let path_display = path.display(); // For safe string formatting
-    let mut file = match File::create(&path) {
-        Err(reason) => {
-            panic!("could not create {}. Error: {}",
-                   path_display,
-                   reason.description())
-        }
-        Ok(file) => file,
-    };
-    write_image(&mut file, &image);
+pub fn save_ppm(image: &Screen, filename: &str) -> io::Result<()> {
+    let mut file = File::create(filename)?;
+    write_image(&mut file, image)
 }
 
-pub fn spawn_saver(rx: Receiver<(String, Screen)>) -> WorkerPool {
-    WorkerPool::new(rx, NUM_WORKERS)
+/// Save the frames received from `rx` on `workers` threads.
+pub fn spawn_saver(rx: Receiver<(String, Screen)>, workers: usize) -> WorkerPool {
+    WorkerPool::new(rx, workers.max(1))
 }
 
-pub fn save_png(image: &Screen, filename: &str) {
+/// Save `image` as a PNG file named `filename`, returning whether it was saved.
+pub fn save_png(image: &Screen, filename: &str) -> bool {
     let tmp_name = format!("{}.ppm", filename);
-    save_ppm(image, &tmp_name);
+    if let Err(e) = save_ppm(image, &tmp_name) {
+        println!("Could not save '{}': {}", tmp_name, e);
+        return false;
+    }
     let start = Instant::now();
     let status = Command::new("convert")
         .arg(&tmp_name)
         .arg(filename)
         .status().expect("failed to execute convert command");
-    if DEBUG {
+    if debug() {
         let elapsed = start.elapsed();
         println!("Convert took: {}ms", elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000);
     }
     if !status.success() {
         println!("Execution of `convert {} {}` failed with status: {}", &tmp_name, filename, status);
     }
+    status.success()
 }
 
 pub fn mkdirp(name: &str) {
@@ -58,14 +54,28 @@ pub fn mkdirp(name: &str) {
     }
 }
 
-pub fn convert_gif(frames: usize, basename: &str) {
+/// Combine the frames of an animation saved in the directory `dir` into `dir/basename.gif`.
+/// Frames which have not been saved (e.g. as only some of the frames have been rendered so far)
+/// are left out.
+pub fn convert_gif(dir: &str, frames: usize, basename: &str) {
     use exec::anim_frame_filename;
     let mut args = Vec::with_capacity(frames + 1);
+    let mut missing = vec![];
     for i in 0..frames {
-        let name = anim_frame_filename(frames, basename, i);
-        args.push(name);
+        let name = anim_frame_filename(dir, frames, basename, i);
+        if Path::new(&name).exists() {
+            args.push(name);
+        } else {
+            missing.push(i);
+        }
+    }
+    let gif_path = format!("{}/{}.gif", dir, basename);
+    if !missing.is_empty() {
+        println!("Frames {} have not been rendered, so they are left out of {}", describe_frames(&missing), gif_path);
+    }
+    if args.is_empty() {
+        return;
     }
-    let gif_path = format!("anim/{}.gif", basename);
     args.push(gif_path.clone());
 
     // Remove the gif file
@@ -75,7 +85,7 @@ pub fn convert_gif(frames: usize, basename: &str) {
         .arg(&gif_path)
         .status().expect("failed to execute rm command");
     if !status0.success() {
-        println!("Execution of `rm anim/{}.gif` failed with status0: {}", basename, status0);
+        println!("Execution of `rm {}` failed with status0: {}", gif_path, status0);
     }
 
     let status1 = Command::new("convert")
@@ -86,10 +96,31 @@ pub fn convert_gif(frames: usize, basename: &str) {
     }
 }
 
-pub fn clean_up_anim_ppms(frames: usize, basename: &str) {
+// Describe a list of frame numbers in increasing order, e.g. "0 to 4, 9"
+fn describe_frames(frames: &[usize]) -> String {
+    let mut runs: Vec<String> = vec![];
+    let mut i = 0;
+    while i < frames.len() {
+        let start = i;
+        while i + 1 < frames.len() && frames[i + 1] == frames[i] + 1 {
+            i += 1;
+        }
+        if i == start {
+            runs.push(frames[start].to_string());
+        } else {
+            runs.push(format!("{} to {}", frames[start], frames[i]));
+        }
+        i += 1;
+    }
+    runs.join(", ")
+}
+
+/// Delete the intermediate .ppm files of the frames numbered `saved` of an animation saved in the
+/// directory `dir`.
+pub fn clean_up_anim_ppms(dir: &str, frames: usize, basename: &str, saved: &[usize]) {
     use exec::anim_frame_filename;
-    for i in 0..frames {
-        let filename = format!("{}.ppm", anim_frame_filename(frames, basename, i));
+    for &i in saved {
+        let filename = format!("{}.ppm", anim_frame_filename(dir, frames, basename, i));
         let status = Command::new("rm")
             .arg("--")
             .arg(&filename)
@@ -125,15 +156,17 @@ pub fn display_image(image: &Screen) {
     println!("Execution of `rm .temp.ppm` exited with status: {}", status1);
 }
 
-pub fn write_image(file: &mut File, image: &Screen) {
+pub fn write_image(file: &mut File, image: &Screen) -> io::Result<()> {
     let start = Instant::now();
     let mut bufwriter = BufWriter::new(&*file);
     // P6 identifies the version of PPM in which colors are represented
     // in binary; as our max color value is 255, each RGB color is 3 bytes
-    bufwriter.write_fmt(format_args!("P6\n{} {} 255\n", WIDTH, HEIGHT));
-    bufwriter.write(image.as_bytes());
-    if DEBUG {
+    write!(bufwriter, "P6\n{} {} 255\n", image.width(), image.height())?;
+    bufwriter.write_all(image.as_bytes())?;
+    bufwriter.flush()?;
+    if debug() {
         let elapsed = start.elapsed();
         println!("Saving took: {}ms {}ns", elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000, elapsed.subsec_nanos() as u64 % 1000000);
     }
+    Ok(())
 }
@@ -6,75 +6,134 @@ use matrix::Matrix;
 use consts::*;
 
 // row-major order
-// The size of the [u8] is WIDTH * HEIGH T* PX_SIZE. A slice is used because
+// The size of the [u8] is width * height * PX_SIZE. A slice is used because
 // allocating an array directly on the heap seems to require excessive jankiness.
-pub struct Screen(Box<[u8]>);
-
-const SCREEN_ROW_SIZE: usize = WIDTH * PX_SIZE;
+pub struct Screen {
+    width: usize,
+    height: usize,
+    data: Box<[u8]>,
+}
 
 impl Screen {
-    pub fn new() -> Screen {
+    /// Make a black screen `width` pixels wide and `height` pixels tall.
+    pub fn new(width: usize, height: usize) -> Screen {
         // Use a Vec to allocate on the heap because Rust's heap api is
         // unstable (grumble grumble...).
-        // TODO: either use an array (not slice) or allow varying screen sizes
-        let vec_data = vec![0u8; WIDTH * HEIGHT * PX_SIZE];
+        let vec_data = vec![0u8; width * height * PX_SIZE];
         let data: Box<[u8]> = vec_data.into_boxed_slice();
-        Screen(data)
+        Screen { width, height, data }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
     }
 
     #[allow(dead_code)]
     pub fn getxy(&self, x: usize, y: usize) -> Color {
-        let row = HEIGHT - y - 1;
-        let i = row * SCREEN_ROW_SIZE + x * PX_SIZE;
+        let row = self.height - y - 1;
+        let i = (row * self.width + x) * PX_SIZE;
         Color {
-            r: self.0[i],
-            g: self.0[i + 1],
-            b: self.0[i + 2]
+            r: self.data[i],
+            g: self.data[i + 1],
+            b: self.data[i + 2]
         }
     }
 
     pub fn setxy(&mut self, x: usize, y: usize, clr: Color) {
-        let row = HEIGHT - y - 1;
-        let i = row * SCREEN_ROW_SIZE + x * PX_SIZE;
-        self.0[i] = clr.r;
-        self.0[i + 1] = clr.g;
-        self.0[i + 2] = clr.b;
+        let row = self.height - y - 1;
+        let i = (row * self.width + x) * PX_SIZE;
+        self.data[i] = clr.r;
+        self.data[i + 1] = clr.g;
+        self.data[i + 2] = clr.b;
     }
 
     pub fn as_bytes(&self) -> &[u8] {
-        &self.0
+        &self.data
     }
 
     pub fn clear_black(&mut self) {
-        for i in 0..WIDTH * HEIGHT * PX_SIZE {
-            self.0[i] = 0;
+        for byte in self.data.iter_mut() {
+            *byte = 0;
         }
     }
 }
 
+/// The sums of the colors of several screens of the same size, for averaging them (e.g. the
+/// sub-frames of a frame with motion blur).
+pub struct ScreenSum {
+    width: usize,
+    height: usize,
+    data: Box<[u32]>,
+    count: u32,
+}
+
+impl ScreenSum {
+    pub fn new(width: usize, height: usize) -> ScreenSum {
+        let vec_data = vec![0u32; width * height * PX_SIZE];
+        ScreenSum { width, height, data: vec_data.into_boxed_slice(), count: 0 }
+    }
+
+    /// Add the colors of `screen`, which must be the same size as the sum.
+    pub fn add(&mut self, screen: &Screen) {
+        assert!(screen.width() == self.width && screen.height() == self.height, "screen is not the size of the sum");
+        for (sum, &byte) in self.data.iter_mut().zip(screen.as_bytes()) {
+            *sum += byte as u32;
+        }
+        self.count += 1;
+    }
+
+    /// A screen with the average of the colors of the screens added (black if there were none).
+    pub fn average(&self) -> Screen {
+        let mut screen = Screen::new(self.width, self.height);
+        if self.count == 0 {
+            return screen;
+        }
+        for (byte, &sum) in screen.data.iter_mut().zip(self.data.iter()) {
+            // Round to the nearest value
+            *byte = ((sum + self.count / 2) / self.count) as u8;
+        }
+        screen
+    }
+}
+
 // row-major order
-// length of [f64] is WIDTH * HEIGHT
-pub struct ZBuffer(Box<[f64]>);
+// length of [f64] is width * height
+pub struct ZBuffer {
+    width: usize,
+    height: usize,
+    data: Box<[f64]>,
+}
 
 impl ZBuffer {
-    pub fn new() -> ZBuffer {
-        use std::f64::INFINITY;
-        let vec_data = vec![-INFINITY; WIDTH * HEIGHT];
-        ZBuffer(vec_data.into_boxed_slice())
+    /// Make an empty z-buffer for a screen `width` pixels wide and `height` pixels tall.
+    pub fn new(width: usize, height: usize) -> ZBuffer {
+        let vec_data = vec![f64::NEG_INFINITY; width * height];
+        ZBuffer { width, height, data: vec_data.into_boxed_slice() }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
     }
 
     pub fn clear(&mut self) {
-        use std::f64::INFINITY;
-        for i in 0..WIDTH * HEIGHT {
-            self.0[i] = -INFINITY;
+        for z in self.data.iter_mut() {
+            *z = f64::NEG_INFINITY;
         }
     }
 
     pub fn maybe_plot(&mut self, x: usize, y: usize, z: f64) -> bool {
-        let row = HEIGHT - y - 1;
-        let i = row * WIDTH + x;
-        if self.0[i] < z {
-            self.0[i] = z;
+        let row = self.height - y - 1;
+        let i = row * self.width + x;
+        if self.data[i] < z {
+            self.data[i] = z;
             true
         } else {
             false
@@ -126,6 +185,147 @@ impl fmt::Display for Color {
     }
 }
 
+/// How strongly a surface reflects one color channel of ambient, diffuse, and specular light.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reflectance {
+    pub ambient: f64,
+    pub diffuse: f64,
+    pub specular: f64,
+}
+
+/// The lighting constants of a surface, as defined by the `constants` command. `intensity`, if
+/// present, is light emitted by the surface itself (r, g, b), added regardless of lighting.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Material {
+    pub r: Reflectance,
+    pub g: Reflectance,
+    pub b: Reflectance,
+    pub intensity: Option<(f64, f64, f64)>,
+}
+
+impl Default for Material {
+    /// The material used for shapes without constants: full ambient and diffuse reflection, and
+    /// no specular reflection.
+    fn default() -> Material {
+        let reflectance = Reflectance { ambient: 1.0, diffuse: 1.0, specular: 0.0 };
+        Material { r: reflectance, g: reflectance, b: reflectance, intensity: None }
+    }
+}
+
+// Exponent applied to the cosine of the angle between the reflected light and the view vector.
+// Higher values make smaller, sharper highlights.
+const SPECULAR_EXPONENT: i32 = 8;
+
+// How far in front of the camera the near and far planes of a perspective projection are.
+// Triangles and edges reaching closer to the camera than NEAR are not drawn.
+const NEAR: f64 = 1.0;
+const FAR: f64 = 100000.0;
+
+/// A camera set by the `camera` or `ortho` command, which decides where shapes are drawn on the
+/// screen. Without one, world x and y are used as pixel coordinates, looking down the -z axis.
+#[derive(Clone)]
+pub struct Camera {
+    eye: Vector,
+    /// The direction the camera looks in
+    look: Vector,
+    /// Moves points from world space into view space, where the camera is at the origin looking
+    /// down the -z axis with +y up
+    view: Matrix,
+    projection: Matrix,
+    orthographic: bool,
+    /// Triangles and edges reaching closer to the camera than this are not drawn
+    near: f64,
+    /// The size of the screen in pixels
+    width: f64,
+    height: f64,
+}
+
+impl Camera {
+    /// A camera at `eye` looking towards `aim`, with `up` pointing up on the screen and a
+    /// vertical field of view of `fov` degrees, for a screen `width` by `height` pixels.
+    pub fn perspective(eye: [f64; 3], aim: [f64; 3], up: [f64; 3], fov: f64, width: usize, height: usize) -> Result<Camera, String> {
+        if !(fov > 0.0 && fov < 180.0) {
+            return Err(format!("The camera's field of view must be between 0 and 180 degrees, not {}", fov));
+        }
+        let projection = Matrix::perspective(fov.to_radians(), width as f64 / height as f64, NEAR, FAR);
+        Camera::new(eye, aim, up, projection, false, NEAR, (width, height))
+    }
+
+    /// An orthographic camera at `eye` looking towards `aim`, with `up` pointing up on the
+    /// screen. `volume` is the left, right, bottom, top, near, and far sides of the box in front
+    /// of the camera which is stretched to fill the screen (of `width` by `height` pixels),
+    /// measured from `eye`.
+    pub fn orthographic(eye: [f64; 3], aim: [f64; 3], up: [f64; 3], volume: [f64; 6], width: usize, height: usize) -> Result<Camera, String> {
+        let [left, right, bottom, top, near, far] = volume;
+        if !(left < right && bottom < top && near < far) {
+            return Err(format!("The camera's view volume must have left < right, bottom < top, and near < far, not {} {} {} {} {} {}",
+                               left, right, bottom, top, near, far));
+        }
+        let projection = Matrix::orthographic(left, right, bottom, top, near, far);
+        Camera::new(eye, aim, up, projection, true, near, (width, height))
+    }
+
+    // `size` is the width and height of the screen
+    fn new(eye: [f64; 3], aim: [f64; 3], up: [f64; 3], projection: Matrix, orthographic: bool, near: f64,
+           size: (usize, usize)) -> Result<Camera, String> {
+        let view = match Matrix::look_at(eye, aim, up) {
+            Some(view) => view,
+            None => return Err(String::from("The camera's eye and aim must be different points, and its up direction must not point along the direction it looks in")),
+        };
+        let eye = Vector::new(eye[0], eye[1], eye[2]);
+        Ok(Camera {
+            eye,
+            look: Vector::new(aim[0], aim[1], aim[2]).sub(eye).normalize(),
+            view,
+            projection,
+            orthographic,
+            near,
+            width: size.0 as f64,
+            height: size.1 as f64,
+        })
+    }
+
+    fn to_view(&self, points: &Matrix) -> Matrix {
+        &self.view * points
+    }
+
+    // Whether the triangle with corners `p`, `q`, and `r` (in view space, counter-clockwise when
+    // seen from the front) faces the camera
+    fn faces(&self, p: [f64; 4], q: [f64; 4], r: [f64; 4]) -> bool {
+        let p = Vector::from_homo(p);
+        let normal = Vector::from_homo(q).sub(p).cross(Vector::from_homo(r).sub(p));
+        if self.orthographic {
+            // Every point is looked at along the -z axis
+            normal.z > 0.0
+        } else {
+            normal.dot(p.scale(-1.0)) > 0.0
+        }
+    }
+
+    // Project a point in view space onto the screen. x and y are in pixels, and z is larger for
+    // points nearer the camera, as the z-buffer expects. Returns None for points too close to
+    // (or behind) the camera.
+    fn to_screen(&self, point: [f64; 4]) -> Option<[f64; 4]> {
+        if -point[2] < self.near {
+            return None;
+        }
+        let clip = self.projection.transform_point(point);
+        // The perspective divide: things further away (with a larger w) are drawn smaller. For
+        // orthographic cameras, w is always 1.
+        let (x, y, z) = (clip[0] / clip[3], clip[1] / clip[3], clip[2] / clip[3]);
+        Some([(x + 1.0) * self.width / 2.0, (y + 1.0) * self.height / 2.0, -z, 1.0])
+    }
+
+    // The direction from `p` (in world space) towards the camera
+    fn to_viewer(&self, p: Vector) -> Vector {
+        if self.orthographic {
+            self.look.scale(-1.0)
+        } else {
+            self.eye.sub(p).normalize()
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct Point {
     pub x: i64,
@@ -204,15 +404,26 @@ impl Vector {
 /// columns are considered the endpoints of a distinct edge
 /// (i.e. [A-start | A-end | B-start | B-end | etc...]).
 ///
-/// All edges are drawn in white.
-pub fn edge_list(image: &mut Screen, edges: &Matrix) {
+/// All edges are drawn in white. With a `camera`, the edges are projected onto the screen, and
+/// edges reaching behind the camera are not drawn.
+pub fn edge_list(image: &mut Screen, edges: &Matrix, camera: Option<&Camera>) {
+    let view_space = camera.map(|camera| (camera, camera.to_view(edges)));
     let mut c = 0;
     while c + 1 < edges.width() {
-        let pcol = edges.col(c);
-        let qcol = edges.col(c + 1);
-        let p = Point::xy(pcol[0] as i64, pcol[1] as i64);
-        let q = Point::xy(qcol[0] as i64, qcol[1] as i64);
-        line(image, p, q, Color::white());
+        let ends = match view_space {
+            Some((camera, ref view_space)) => {
+                match (camera.to_screen(view_space.col(c)), camera.to_screen(view_space.col(c + 1))) {
+                    (Some(pcol), Some(qcol)) => Some((pcol, qcol)),
+                    _ => None,
+                }
+            },
+            None => Some((edges.col(c), edges.col(c + 1))),
+        };
+        if let Some((pcol, qcol)) = ends {
+            let p = Point::xy(pcol[0] as i64, pcol[1] as i64);
+            let q = Point::xy(qcol[0] as i64, qcol[1] as i64);
+            line(image, p, q, Color::white());
+        }
         c += 2;
     }
 }
@@ -253,11 +464,17 @@ fn fmax(f: f64, g: f64) -> f64 {
     }
 }
 
-pub fn triangle_list(image: &mut Screen, z_buffer: &mut ZBuffer, triangles: &Matrix, lighting: &LightingData) {
+/// Draw the triangles in a triangle list matrix (three columns for each triangle, with corners
+/// counter-clockwise when seen from the front), lit by `lighting`. Triangles facing away from
+/// the viewer are not drawn. With a `camera`, this is decided in view space, and the triangles
+/// are then projected onto the screen.
+pub fn triangle_list(image: &mut Screen, z_buffer: &mut ZBuffer, triangles: &Matrix, lighting: &LightingData, material: &Material, camera: Option<&Camera>) {
     let ambient_clr = match lighting.ambient {
-        Some(c) => (c.0 * 1.0, c.1 * 1.0, c.2 * 1.0),
+        Some(c) => c,
         None => (0.0, 0.0, 0.0)
     };
+    let emitted_clr = material.intensity.unwrap_or((0.0, 0.0, 0.0));
+    let view_space = camera.map(|camera| (camera, camera.to_view(triangles)));
     // Iterate over each triplet of 3 columns in `triangles`
     let mut i = 0;
     while i + 2 < triangles.width() {
@@ -271,26 +488,61 @@ pub fn triangle_list(image: &mut Screen, z_buffer: &mut ZBuffer, triangles: &Mat
         let r = Vector::from_homo(rcol);
         let normal = q.sub(p).cross(r.sub(p)).normalize();
 
-        // Get diffuse lighting values for this triangle
-        let mut diffuse_clr = (0.0, 0.0, 0.0);
-        for &(r, g, b, x, y, z) in &lighting.lights {
-            // Intensity of light (r, g, or b) is scaled by strength (cosine of
-            // the angle between the light and the normal to the surface)
-            let strength = Vector::new(-x, -y, -z).normalize().dot(normal);
-            if !strength.is_nan() && strength > 0.0 {
-                // check for NaN so x = y = z = 0 doesn't screw up everything
-                diffuse_clr.0 += strength * r;
-                diffuse_clr.1 += strength * g;
-                diffuse_clr.2 += strength * b;
-            }
-        }
+        // Find the corners of the triangle on the screen if it is facing the viewer, and the
+        // vector towards the viewer
+        let (corners, view) = match view_space {
+            Some((camera, ref view_space)) => {
+                let (pv, qv, rv) = (view_space.col(i), view_space.col(i + 1), view_space.col(i + 2));
+                let corners = if camera.faces(pv, qv, rv) {
+                    match (camera.to_screen(pv), camera.to_screen(qv), camera.to_screen(rv)) {
+                        (Some(ps), Some(qs), Some(rs)) => Some((ps, qs, rs)),
+                        // Triangles reaching behind the camera are not drawn
+                        _ => None,
+                    }
+                } else {
+                    None
+                };
+                (corners, camera.to_viewer(p.add(q).add(r).scale(1.0 / 3.0)))
+            },
+            // The viewer looks down the -z axis, so the vector towards the viewer is +z
+            None => {
+                let corners = if normal.z > 0.0 { Some((pcol, qcol, rcol)) } else { None };
+                (corners, Vector::new(0.0, 0.0, 1.0))
+            },
+        };
 
         // If it is facing us, render it:
-        if normal.z > 0.0 {
-            scanline(image, z_buffer, pcol, qcol, rcol, Color {
-                r: fclamp_u8(ambient_clr.0 + diffuse_clr.0),
-                g: fclamp_u8(ambient_clr.1 + diffuse_clr.1),
-                b: fclamp_u8(ambient_clr.2 + diffuse_clr.2),
+        if let Some((ps, qs, rs)) = corners {
+            // Get diffuse and specular lighting values for this triangle
+            let mut diffuse_clr = (0.0, 0.0, 0.0);
+            let mut specular_clr = (0.0, 0.0, 0.0);
+            for &(r, g, b, x, y, z) in &lighting.lights {
+                // Intensity of light (r, g, or b) is scaled by strength (cosine of
+                // the angle between the light and the normal to the surface)
+                let to_light = Vector::new(-x, -y, -z).normalize();
+                let strength = to_light.dot(normal);
+                if !strength.is_nan() && strength > 0.0 {
+                    // check for NaN so x = y = z = 0 doesn't screw up everything
+                    diffuse_clr.0 += strength * r;
+                    diffuse_clr.1 += strength * g;
+                    diffuse_clr.2 += strength * b;
+
+                    // Reflect the light about the normal to see how much of it reaches the viewer
+                    let reflected = normal.scale(2.0 * strength).sub(to_light);
+                    let shine = reflected.dot(view);
+                    if shine > 0.0 {
+                        let shine = shine.powi(SPECULAR_EXPONENT);
+                        specular_clr.0 += shine * r;
+                        specular_clr.1 += shine * g;
+                        specular_clr.2 += shine * b;
+                    }
+                }
+            }
+
+            scanline(image, z_buffer, ps, qs, rs, Color {
+                r: fclamp_u8(shade(&material.r, ambient_clr.0, diffuse_clr.0, specular_clr.0) + emitted_clr.0),
+                g: fclamp_u8(shade(&material.g, ambient_clr.1, diffuse_clr.1, specular_clr.1) + emitted_clr.1),
+                b: fclamp_u8(shade(&material.b, ambient_clr.2, diffuse_clr.2, specular_clr.2) + emitted_clr.2),
             });
         }
         // Advance to the next triangle
@@ -298,6 +550,11 @@ pub fn triangle_list(image: &mut Screen, z_buffer: &mut ZBuffer, triangles: &Mat
     }
 }
 
+/// Combine the ambient, diffuse, and specular light hitting a surface for one color channel.
+fn shade(reflectance: &Reflectance, ambient: f64, diffuse: f64, specular: f64) -> f64 {
+    reflectance.ambient * ambient + reflectance.diffuse * diffuse + reflectance.specular * specular
+}
+
 /// Note: top, mid, and low are not required to be passed in any order.
 pub fn scanline(img: &mut Screen, z_buffer: &mut ZBuffer, mut top: [f64; 4], mut mid: [f64; 4], mut low: [f64; 4], clr: Color) {
     // Sort `top`, `mid`, and `low` into the order their names imply
@@ -380,7 +637,7 @@ fn fclamp(min: f64, x: f64, max: f64) -> f64 {
 fn flat_line(img: &mut Screen, z_buffer: &mut ZBuffer, mut fx0: f64, mut fx1: f64, y: i64, mut fz0: f64, mut fz1: f64, clr: Color) {
     use std::cmp::{ min, max };
     // Return if y is offscreen
-    if y < 0 || y >= HEIGHT as i64 {
+    if y < 0 || y >= img.height() as i64 {
         return;
     }
     // Order fx0 and fx1, and fz0 and fz1 (fz0 is the z coord at fx0, fz1 is z at fx1)
@@ -388,9 +645,14 @@ fn flat_line(img: &mut Screen, z_buffer: &mut ZBuffer, mut fx0: f64, mut fx1: f6
         swap(&mut fx0, &mut fx1);
         swap(&mut fz0, &mut fz1);
     }
+    // Return if the whole line is offscreen, rather than drawing it along the edge
+    let max_x = (img.width() - 1) as f64;
+    if fx1 < 0.0 || fx0 > max_x {
+        return;
+    }
     // Redefine variables as usizes and clamp x within the screen
-    let x0 = fclamp(0.0, fx0, (WIDTH - 1) as f64) as usize;
-    let x1 = fclamp(0.0, fx1, (WIDTH - 1) as f64) as usize;
+    let x0 = fclamp(0.0, fx0, max_x) as usize;
+    let x1 = fclamp(0.0, fx1, max_x) as usize;
     let y = y as usize; // overflow is not an issue as we check bounds of y above
 
     let mut z = fz0;
@@ -432,8 +694,8 @@ pub fn line(image: &mut Screen, start: Point, end: Point, color: Color) {
 
 /// If the point `p` is within the width and height of `image`, plot `color` at `p`.
 pub fn plot_if_visible(image: &mut Screen, p: Point, color: Color) {
-    let within_x = p.x >= 0 && p.x < WIDTH as i64;
-    let within_y = p.y >= 0 && p.y < HEIGHT as i64;
+    let within_x = p.x >= 0 && p.x < image.width() as i64;
+    let within_y = p.y >= 0 && p.y < image.height() as i64;
     if within_x && within_y {
         image.setxy(p.x as usize, p.y as usize, color);
     }
@@ -0,0 +1,130 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use check;
+use parse;
+use exec::{ self, Frame, Rendered };
+use render::Screen;
+use source::Sources;
+
+/// A script to render, along with the files it includes.
+pub struct Scene {
+    sources: Sources,
+}
+
+impl Scene {
+    /// Read the script at `path` and the files it includes.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Scene, Vec<String>> {
+        let path = path.as_ref();
+        let mut text = String::new();
+        if let Err(e) = File::open(path).and_then(|mut file| file.read_to_string(&mut text)) {
+            return Err(vec![format!("Could not read '{}': {}", path.display(), e)]);
+        }
+        Scene::from_text(path, text)
+    }
+
+    /// Make a scene from the text of a script, reading the files it includes. `path` is where the
+    /// script would be read from: it is used in error messages, and paths in `include` commands
+    /// are relative to its directory.
+    pub fn from_text<P: AsRef<Path>>(path: P, text: String) -> Result<Scene, Vec<String>> {
+        match Sources::from_text(path.as_ref(), text) {
+            Ok(sources) => Ok(Scene { sources }),
+            Err(errors) => Err(errors.iter().map(|e| e.to_string()).collect()),
+        }
+    }
+
+    /// Find the syntax errors and other problems in the script, without rendering it.
+    pub fn check(&self) -> Vec<String> {
+        check::check_sources(&self.sources)
+    }
+
+    /// Find the problems in the script which don't stop it from being rendered, but may make it
+    /// look different than expected (e.g. knobs which have no value in some frames).
+    pub fn warnings(&self) -> Vec<String> {
+        match parse::parse_sources(&self.sources) {
+            Ok((cmds, locations)) => {
+                check::check(&cmds, &locations, Some(&self.sources)).iter()
+                    .filter(|p| !p.error).map(|p| p.to_string()).collect()
+            },
+            // Errors are given by `check`, or by `Renderer` when rendering
+            Err(_) => vec![],
+        }
+    }
+
+    pub fn sources(&self) -> &Sources {
+        &self.sources
+    }
+}
+
+/// Renders scenes into memory, either all at once or a frame at a time.
+pub struct Renderer {
+    threads: usize,
+    frames: Option<(usize, usize)>,
+    output_dir: String,
+    size: Option<(usize, usize)>,
+    skip_unchanged: bool,
+}
+
+impl Renderer {
+    /// A renderer that renders every frame on one thread, naming frames to be saved in `anim`.
+    pub fn new() -> Renderer {
+        Renderer { threads: 1, frames: None, output_dir: String::from("anim"), size: None, skip_unchanged: false }
+    }
+
+    /// Render the frames of animations on `threads` threads.
+    pub fn threads(mut self, threads: usize) -> Renderer {
+        self.threads = threads;
+        self
+    }
+
+    /// Only render the frames of animations from `first` to `last` (inclusive).
+    pub fn frames(mut self, first: usize, last: usize) -> Renderer {
+        self.frames = Some((first, last));
+        self
+    }
+
+    /// Render images `width` by `height` pixels, whatever size the scene's `resolution` command
+    /// gives.
+    pub fn resolution(mut self, width: usize, height: usize) -> Renderer {
+        self.size = Some((width, height));
+        self
+    }
+
+    /// Name the frames of animations (see `Frame::filename`) to be saved in the directory `dir`.
+    pub fn output_dir(mut self, dir: &str) -> Renderer {
+        self.output_dir = String::from(dir);
+        self
+    }
+
+    /// Skip the frames of animations that are already saved in the output directory and would
+    /// look the same, according to its `cache::FrameCache`. Skipped frames are not given by
+    /// `render` or passed to `render_each`'s `on_frame`.
+    pub fn skip_unchanged(mut self, skip: bool) -> Renderer {
+        self.skip_unchanged = skip;
+        self
+    }
+
+    /// Render `scene`, giving its image if it is a still image, or its frames (in order) if it is
+    /// an animation.
+    pub fn render(&self, scene: &Scene) -> Result<Vec<Screen>, String> {
+        let mut frames = vec![];
+        match self.render_each(scene, |frame| frames.push(frame.screen))? {
+            Rendered::Still(screen) => Ok(vec![screen]),
+            Rendered::Animation { .. } => Ok(frames),
+        }
+    }
+
+    /// Render `scene`, passing each frame of an animation to `on_frame` as soon as it and the
+    /// frames before it are done.
+    pub fn render_each<'s, F: FnMut(Frame)>(&self, scene: &'s Scene, on_frame: F) -> Result<Rendered<'s>, String> {
+        let cmds = exec::load(&scene.sources)?;
+        exec::render(&cmds, self.threads, self.frames, &self.output_dir, self.size, self.skip_unchanged, on_frame)
+    }
+}
+
+impl Default for Renderer {
+    fn default() -> Renderer {
+        Renderer::new()
+    }
+}
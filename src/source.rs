@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{ Path, PathBuf };
+
+use parse::ParseError;
+
+/// A script and every file it includes (directly or indirectly). All of the files are read before
+/// parsing so that commands can borrow their text.
+pub struct Sources {
+    files: Vec<SourceFile>,
+    /// The index of the file included by each `include` command, keyed by the index of the file
+    /// containing the command and the (1-based) number of the line it is on
+    includes: HashMap<(usize, usize), usize>,
+}
+
+struct SourceFile {
+    /// The path as given by the user or by an `include` command, used in error messages
+    path: PathBuf,
+    /// The canonical path, used to detect cycles and files included more than once (None for
+    /// scripts not read from a file, e.g. from stdin)
+    canonical: Option<PathBuf>,
+    text: String,
+    /// The file and line of the `include` command the file was first included by (None for the
+    /// root script)
+    included_by: Option<(usize, usize)>,
+}
+
+impl Sources {
+    /// Load every file included by the script `text`. Paths in `include` commands are relative to
+    /// the directory of the including file, so `path` (the path `text` was read from) is used to
+    /// resolve the root script's includes.
+    pub fn from_text(path: &Path, text: String) -> Result<Sources, Vec<ParseError>> {
+        let mut sources = Sources {
+            files: vec![SourceFile {
+                path: path.to_path_buf(),
+                canonical: path.canonicalize().ok(),
+                text,
+                included_by: None,
+            }],
+            includes: HashMap::new(),
+        };
+        let mut errors = vec![];
+        sources.load_includes(0, &mut vec![], &mut errors);
+        if errors.is_empty() {
+            Ok(sources)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The text of the script the sources were loaded from.
+    pub fn root(&self) -> &str {
+        &self.files[0].text
+    }
+
+    /// The text of the `n`th file.
+    pub fn text(&self, n: usize) -> &str {
+        &self.files[n].text
+    }
+
+    /// The path of the `n`th file, for error messages.
+    pub fn path(&self, n: usize) -> &Path {
+        &self.files[n].path
+    }
+
+    /// The paths of the script and of every file it includes.
+    pub fn paths(&self) -> Vec<&Path> {
+        self.files.iter().map(|f| f.path.as_path()).collect()
+    }
+
+    /// The path and line of each `include` command that led to the `n`th file being included,
+    /// innermost first. It is empty for the root script.
+    pub fn include_chain(&self, n: usize) -> Vec<(String, usize)> {
+        let mut chain = vec![];
+        let mut file = n;
+        while let Some((including, line)) = self.files[file].included_by {
+            chain.push((self.files[including].path.display().to_string(), line));
+            file = including;
+        }
+        chain
+    }
+
+    /// The index of the file included by the `include` command on line `line` of file `file`.
+    pub fn included(&self, file: usize, line: usize) -> Option<usize> {
+        self.includes.get(&(file, line)).cloned()
+    }
+
+    // Load the files included by the `file`th file, and the files they include. `stack` is the
+    // list of files currently being included, innermost last, which must not be included again.
+    fn load_includes(&mut self, file: usize, stack: &mut Vec<usize>, errors: &mut Vec<ParseError>) {
+        stack.push(file);
+        let including = self.files[file].path.clone();
+        let chain = self.include_chain(file);
+        for (line, column, path) in include_commands(&self.files[file].text) {
+            let error = |expected: String| {
+                ParseError {
+                    file: None,
+                    line,
+                    column,
+                    lexeme: Some(path.clone()),
+                    expected,
+                    included_from: vec![],
+                }.in_file(&including).included_from(chain.clone())
+            };
+            let resolved = match including.parent() {
+                Some(dir) => dir.join(&path),
+                None => PathBuf::from(&path),
+            };
+            let canonical = match resolved.canonicalize() {
+                Ok(canonical) => canonical,
+                Err(e) => {
+                    errors.push(error(format!("a file that can be read ({})", e)));
+                    continue;
+                },
+            };
+
+            // Include a file only once, even if several files include it
+            let existing = self.files.iter().position(|f| f.canonical.as_ref() == Some(&canonical));
+            if let Some(included) = existing {
+                if stack.contains(&included) {
+                    let cycle: Vec<String> = stack[stack.iter().position(|&f| f == included).unwrap()..]
+                        .iter()
+                        .map(|&f| self.files[f].path.display().to_string())
+                        .collect();
+                    errors.push(error(format!("a file that does not include this one (include cycle: {} -> {})",
+                                              cycle.join(" -> "), resolved.display())));
+                } else {
+                    self.includes.insert((file, line), included);
+                }
+                continue;
+            }
+
+            let mut text = String::new();
+            if let Err(e) = File::open(&canonical).and_then(|mut f| f.read_to_string(&mut text)) {
+                errors.push(error(format!("a file that can be read ({})", e)));
+                continue;
+            }
+            let included = self.files.len();
+            self.files.push(SourceFile {
+                path: resolved,
+                canonical: Some(canonical),
+                text,
+                included_by: Some((file, line)),
+            });
+            self.includes.insert((file, line), included);
+            self.load_includes(included, stack, errors);
+        }
+        stack.pop();
+    }
+}
+
+// Find the `include` commands in `text`, giving the line number, the column of the path, and the
+// path of each. Malformed commands are left for the parser to report.
+fn include_commands(text: &str) -> Vec<(usize, usize, String)> {
+    let mut commands = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let mut words = line.split_whitespace();
+        if let (Some("include"), Some(path)) = (words.next(), words.next()) {
+            let offset = path.as_ptr() as usize - line.as_ptr() as usize;
+            commands.push((i + 1, line[..offset].chars().count() + 1, path.to_string()));
+        }
+    }
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use parse;
+
+    // A new directory for the files of one test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("graphics-source-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Write the files `files` (paths and texts) in `dir`, and load the first as the root script
+    fn load(dir: &Path, files: &[(&str, &str)]) -> Result<Sources, Vec<ParseError>> {
+        for &(path, text) in files {
+            fs::write(dir.join(path), text).unwrap();
+        }
+        let root = dir.join(files[0].0);
+        Sources::from_text(&root, files[0].1.to_string())
+    }
+
+    #[test]
+    fn nested_includes() {
+        let dir = test_dir("nested");
+        let sources = load(&dir, &[
+            ("script", "push\ninclude a.mdl\n"),
+            ("a.mdl", "// a\n\ninclude b.mdl\n"),
+            ("b.mdl", "box 0 0 0 1 1 1\n"),
+        ]).unwrap();
+        assert_eq!(sources.paths().len(), 3);
+        let a = sources.included(0, 2).unwrap();
+        let b = sources.included(a, 3).unwrap();
+        assert_eq!(sources.text(b), "box 0 0 0 1 1 1\n");
+        assert_eq!(sources.include_chain(0), vec![]);
+        assert_eq!(sources.include_chain(b), vec![
+            (dir.join("a.mdl").display().to_string(), 3),
+            (dir.join("script").display().to_string(), 2),
+        ]);
+    }
+
+    #[test]
+    fn files_included_twice_are_loaded_once() {
+        let dir = test_dir("twice");
+        let sources = load(&dir, &[
+            ("script", "include a.mdl\ninclude b.mdl\ninclude a.mdl\n"),
+            ("a.mdl", "push\n"),
+            ("b.mdl", "include a.mdl\n"),
+        ]).unwrap();
+        assert_eq!(sources.paths().len(), 3);
+        let a = sources.included(0, 1).unwrap();
+        assert_eq!(sources.included(0, 3), Some(a));
+        assert_eq!(sources.included(sources.included(0, 2).unwrap(), 1), Some(a));
+    }
+
+    #[test]
+    fn include_cycles() {
+        let dir = test_dir("cycle");
+        let errors = load(&dir, &[
+            ("script", "include a.mdl\n"),
+            ("a.mdl", "include b.mdl\n"),
+            ("b.mdl", "push\ninclude a.mdl\n"),
+        ]).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].file, Some(dir.join("b.mdl").display().to_string()));
+        assert_eq!((errors[0].line, errors[0].column), (2, 9));
+        assert!(errors[0].expected.contains("include cycle"), "{}", errors[0]);
+        assert_eq!(errors[0].included_from, vec![
+            (dir.join("a.mdl").display().to_string(), 1),
+            (dir.join("script").display().to_string(), 1),
+        ]);
+    }
+
+    #[test]
+    fn files_including_themselves() {
+        let dir = test_dir("self");
+        let errors = load(&dir, &[("script", "include script\n")]).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].expected.contains("include cycle"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn missing_files() {
+        let dir = test_dir("missing");
+        let errors = load(&dir, &[("script", "push\ninclude nothing.mdl\n")]).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 2);
+        assert_eq!(errors[0].lexeme, Some(String::from("nothing.mdl")));
+        assert!(errors[0].expected.starts_with("a file that can be read"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn parse_errors_in_included_files() {
+        let dir = test_dir("parse-error");
+        let sources = load(&dir, &[
+            ("script", "push\ninclude a.mdl\n"),
+            ("a.mdl", "include b.mdl\n"),
+            ("b.mdl", "box 0 0 0 1 1\n"),
+        ]).unwrap();
+        let errors = parse::parse_sources(&sources).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), format!(
+            "{}, line 1, column 14: expected a number, found end of line\n    included from {}, line 1\n    included from {}, line 2",
+            dir.join("b.mdl").display(), dir.join("a.mdl").display(), dir.join("script").display()));
+    }
+}
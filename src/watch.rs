@@ -0,0 +1,54 @@
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::thread;
+use std::time::{ Duration, SystemTime };
+
+/// Watches files for changes by polling their modification times.
+pub struct Watcher {
+    /// Each file watched, with its modification time when it started being watched (None if it
+    /// could not be read then, e.g. if it did not exist)
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl Watcher {
+    /// A watcher that is not watching any files yet.
+    pub fn new() -> Watcher {
+        Watcher { files: vec![] }
+    }
+
+    /// Watch `paths` instead of the files watched before, as they are now. Files that are
+    /// changed after this (including being created or deleted) count as changed.
+    pub fn watch<P: AsRef<Path>>(&mut self, paths: &[P]) {
+        self.files = paths.iter().map(|path| {
+            let path = path.as_ref();
+            (path.to_path_buf(), modified(path))
+        }).collect();
+    }
+
+    /// The paths of the files being watched.
+    pub fn paths(&self) -> Vec<&Path> {
+        self.files.iter().map(|(path, _)| path.as_path()).collect()
+    }
+
+    /// Whether any of the files has changed since it started being watched.
+    pub fn changed(&self) -> bool {
+        self.files.iter().any(|&(ref path, time)| modified(path) != time)
+    }
+
+    /// Wait until one of the files changes, checking every `interval`.
+    pub fn wait(&self, interval: Duration) {
+        while !self.changed() {
+            thread::sleep(interval);
+        }
+    }
+}
+
+impl Default for Watcher {
+    fn default() -> Watcher {
+        Watcher::new()
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
@@ -6,7 +6,7 @@ use render::Screen;
 use ppm;
 
 pub struct WorkerPool {
-    pub threads: Vec<JoinHandle<()>>,
+    pub threads: Vec<JoinHandle<Vec<String>>>,
     rx: Arc<Mutex<Receiver<(String, Screen)>>>,
 }
 
@@ -23,24 +23,32 @@ impl WorkerPool {
         let amrx = self.rx.clone();
         let handle = thread::spawn(move || {
             let mrx = amrx.as_ref();
+            // The files this worker saved successfully
+            let mut saved = vec![];
             loop {
                 let lock = mrx.lock().unwrap();
                 let next = (*lock).iter().next();
                 drop(lock);
                 if let Some((filename, screen)) = next {
-                    ppm::save_png(&screen, &filename);
+                    if ppm::save_png(&screen, &filename) {
+                        saved.push(filename);
+                    }
                 } else {
                     break;
                 }
             }
+            saved
         });
         self.threads.push(handle);
     }
 
-    pub fn join(self) -> thread::Result<()> {
+    /// Wait for the workers to save every frame sent to them, giving the names of the files that
+    /// were saved successfully.
+    pub fn join(self) -> thread::Result<Vec<String>> {
+        let mut saved = vec![];
         for handle in self.threads {
-            handle.join()?;
+            saved.extend(handle.join()?);
         }
-        Ok(())
+        Ok(saved)
     }
 }